@@ -1,9 +1,20 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
 use lazy_static::lazy_static;
 use moka::future::Cache as GenericCache;
+use moka::notification::RemovalCause;
 use tokio::sync::Mutex;
 
 use crate::{
+    changes::CHANGES,
     config::INFRINGEMENT_DURATION,
+    features::{
+        email,
+        history::archive,
+        review::{retain_evicted, with_review},
+        webhooks::{self, WebhookEvent},
+    },
     reaktor::{drones::DronesDocument, pilots::Pilot},
     Infringement,
 };
@@ -20,6 +31,56 @@ lazy_static! {
             .max_capacity(10_000)
             // Infringements are automatically deleted after [INFRINGEMENT_DURATION]
             .time_to_live(INFRINGEMENT_DURATION)
+            // Infringements that leave the cache are kept in the history, whether they
+            // expired, were evicted for space or were cleared when a replay restarted
+            .eviction_listener_with_queued_delivery_mode(|_, infringement, cause| {
+                if cause != RemovalCause::Replaced {
+                    // With its review, so dismissed infringements stay dismissed
+                    archive(&with_review(infringement.clone()));
+                    webhooks::notify(WebhookEvent::InfringementEnded, &infringement, None);
+                    email::notify(WebhookEvent::InfringementEnded, &infringement, None);
+                }
+                // Reviewed infringements stay listed after they leave the cache
+                if cause != RemovalCause::Replaced && !retain_evicted(&infringement) {
+                    CHANGES.lock().unwrap().delete(&infringement);
+                }
+                INFRINGEMENTS_REVISION.bump();
+            })
             .build()
     );
 }
+
+lazy_static! {
+    /// Changes every time [INFRINGEMENTS] changes
+    pub static ref INFRINGEMENTS_REVISION: Revision = Revision::default();
+}
+
+/// A counter that is bumped on every change, remembering when the last change happened
+#[derive(Debug)]
+pub struct Revision {
+    counter: AtomicU64,
+    modified_at: std::sync::Mutex<SystemTime>,
+}
+
+impl Default for Revision {
+    fn default() -> Self {
+        Self {
+            counter: AtomicU64::new(0),
+            modified_at: std::sync::Mutex::new(SystemTime::now()),
+        }
+    }
+}
+
+impl Revision {
+    pub fn bump(&self) {
+        let mut modified_at = self.modified_at.lock().unwrap();
+        self.counter.fetch_add(1, Ordering::SeqCst);
+        *modified_at = SystemTime::now();
+    }
+
+    /// The current revision and the time it was reached
+    pub fn get(&self) -> (u64, SystemTime) {
+        let modified_at = self.modified_at.lock().unwrap();
+        (self.counter.load(Ordering::SeqCst), *modified_at)
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use paperclip::actix::Apiv2Schema;
+use serde::Serialize;
+
+use crate::Infringement;
+
+/// How many deletions are remembered, clients that fall further behind have to resync
+const MAX_TOMBSTONES: usize = 10_000;
+
+lazy_static! {
+    /// Every change made to [crate::cache::INFRINGEMENTS], in order
+    pub static ref CHANGES: Mutex<ChangeLog> = Mutex::new(ChangeLog::default());
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    /// The infringement was added or updated
+    Upsert,
+    /// The infringement expired or was cleared from the cache
+    Delete,
+}
+
+#[derive(Serialize, Debug, Clone, Apiv2Schema)]
+pub struct Change {
+    /// `<epoch>:<sequence number>`, the sequence number increases with every change
+    #[openapi(example = "18f3a2b4c5d6e7f8:42")]
+    pub cursor: String,
+    pub kind: ChangeKind,
+    pub drone_serial_number: String,
+    /// The new state of the infringement, missing for deletions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub infringement: Option<Infringement>,
+}
+
+/// The changes a client has not seen yet
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct ChangesResponse {
+    /// When true the client has to drop its state before applying the changes,
+    /// either because it fell too far behind or because the server restarted
+    pub reset: bool,
+    /// Only the latest change of every drone is included
+    pub changes: Vec<Change>,
+    /// Pass as `since` to get the following changes
+    #[openapi(example = "18f3a2b4c5d6e7f8:42")]
+    pub next_cursor: String,
+}
+
+/// Keeps only the latest change of every drone, so the log never grows
+/// beyond the cache size and [MAX_TOMBSTONES] deletions
+#[derive(Debug)]
+pub struct ChangeLog {
+    /// Differs on every start, sequence numbers restart from zero with a new epoch
+    epoch: String,
+    cursor: u64,
+    changes: BTreeMap<u64, Change>,
+    latest: HashMap<String, u64>,
+    tombstones: VecDeque<u64>,
+    /// Changes at or before this cursor may have been forgotten
+    horizon: u64,
+}
+
+impl Default for ChangeLog {
+    fn default() -> Self {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self::with_epoch(format!("{:x}", started_at ^ std::process::id() as u64))
+    }
+}
+
+impl ChangeLog {
+    fn with_epoch(epoch: String) -> Self {
+        Self {
+            epoch,
+            cursor: 0,
+            changes: BTreeMap::new(),
+            latest: HashMap::new(),
+            tombstones: VecDeque::new(),
+            horizon: 0,
+        }
+    }
+
+    fn format_cursor(&self, cursor: u64) -> String {
+        format!("{}:{cursor}", self.epoch)
+    }
+
+    fn push(&mut self, drone_serial_number: &str, infringement: Option<Infringement>) {
+        self.cursor += 1;
+        if let Some(previous) = self
+            .latest
+            .insert(drone_serial_number.to_string(), self.cursor)
+        {
+            self.changes.remove(&previous);
+        }
+        let kind = match infringement {
+            Some(_) => ChangeKind::Upsert,
+            None => ChangeKind::Delete,
+        };
+        if kind == ChangeKind::Delete {
+            self.tombstones.push_back(self.cursor);
+        }
+        self.changes.insert(
+            self.cursor,
+            Change {
+                cursor: self.format_cursor(self.cursor),
+                kind,
+                drone_serial_number: drone_serial_number.to_string(),
+                infringement,
+            },
+        );
+        self.prune();
+    }
+
+    fn prune(&mut self) {
+        while self.tombstones.len() > MAX_TOMBSTONES {
+            let Some(cursor) = self.tombstones.pop_front() else {
+                break;
+            };
+            // The tombstone may already have been replaced by a newer change
+            if let Some(change) = self.changes.get(&cursor) {
+                if change.kind == ChangeKind::Delete {
+                    self.latest.remove(&change.drone_serial_number);
+                    self.changes.remove(&cursor);
+                }
+            }
+            self.horizon = cursor;
+        }
+    }
+
+    /// Record that an infringement was added or updated
+    pub fn upsert(&mut self, infringement: &Infringement) {
+        self.push(
+            &infringement.drone_serial_number,
+            Some(infringement.clone()),
+        );
+    }
+
+    /// Record that an infringement left the cache. Removals are delivered asynchronously,
+    /// so they are ignored when the infringement has been updated since
+    pub fn delete(&mut self, infringement: &Infringement) {
+        let superseded = self
+            .latest
+            .get(&infringement.drone_serial_number)
+            .and_then(|cursor| self.changes.get(cursor))
+            .is_some_and(|change| match &change.infringement {
+                Some(latest) => latest.updated_at != infringement.updated_at,
+                None => true,
+            });
+        if !superseded {
+            self.push(&infringement.drone_serial_number, None);
+        }
+    }
+
+    /// Every change after the cursor `since`, or every change if there is none.
+    /// Cursors of an earlier run of the server reset the client
+    pub fn since(&self, since: Option<&str>) -> Result<ChangesResponse> {
+        let since = match since {
+            None => Some(0),
+            Some(since) => {
+                let (epoch, cursor) = since
+                    .split_once(':')
+                    .and_then(|(epoch, cursor)| Some((epoch, cursor.parse().ok()?)))
+                    .with_context(|| format!("Invalid cursor \"{since}\""))?;
+                (epoch == self.epoch).then_some(cursor)
+            }
+        };
+        let since = since.filter(|since| *since >= self.horizon && *since <= self.cursor);
+        Ok(ChangesResponse {
+            reset: since.is_none(),
+            changes: self
+                .changes
+                .range(since.unwrap_or_default() + 1..)
+                .map(|(_, change)| change.clone())
+                .collect(),
+            next_cursor: self.format_cursor(self.cursor),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::test_fixtures::infringement;
+
+    fn serials(response: &ChangesResponse) -> Vec<(&str, ChangeKind)> {
+        response
+            .changes
+            .iter()
+            .map(|c| (c.drone_serial_number.as_str(), c.kind))
+            .collect()
+    }
+
+    #[test]
+    fn only_the_latest_change_after_the_cursor_is_returned() {
+        let at = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let mut log = ChangeLog::with_epoch("a".to_string());
+        log.upsert(&infringement("SN-1", at));
+        let first = log.since(None).unwrap();
+        assert!(!first.reset);
+        assert_eq!(first.next_cursor, "a:1");
+        assert_eq!(serials(&first), [("SN-1", ChangeKind::Upsert)]);
+
+        log.upsert(&infringement("SN-2", at));
+        log.upsert(&infringement("SN-2", at + chrono::Duration::seconds(2)));
+        log.delete(&infringement("SN-1", at));
+        let next = log.since(Some(&first.next_cursor)).unwrap();
+        assert!(!next.reset);
+        assert_eq!(next.next_cursor, "a:4");
+        assert_eq!(
+            serials(&next),
+            [("SN-2", ChangeKind::Upsert), ("SN-1", ChangeKind::Delete)]
+        );
+        assert!(log.since(Some("a:4")).unwrap().changes.is_empty());
+    }
+
+    #[test]
+    fn superseded_deletions_are_ignored() {
+        let at = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let mut log = ChangeLog::with_epoch("a".to_string());
+        log.upsert(&infringement("SN-1", at));
+        log.upsert(&infringement("SN-1", at + chrono::Duration::seconds(2)));
+        // The eviction of the first version arrives after the update
+        log.delete(&infringement("SN-1", at));
+        let response = log.since(None).unwrap();
+        assert_eq!(response.next_cursor, "a:2");
+        assert_eq!(serials(&response), [("SN-1", ChangeKind::Upsert)]);
+    }
+
+    #[test]
+    fn cursors_of_another_epoch_reset_the_client() {
+        let at = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let mut log = ChangeLog::with_epoch("b".to_string());
+        log.upsert(&infringement("SN-1", at));
+        // A client that saw 5 changes before the restart
+        for since in ["a:5", "a:0"] {
+            let response = log.since(Some(since)).unwrap();
+            assert!(response.reset, "{since}");
+            assert_eq!(serials(&response), [("SN-1", ChangeKind::Upsert)]);
+        }
+        // From the future
+        assert!(log.since(Some("b:2")).unwrap().reset);
+        assert!(!log.since(Some("b:1")).unwrap().reset);
+        assert!(log.since(Some("b:x")).is_err());
+        assert!(log.since(Some("nonsense")).is_err());
+        assert!(log.since(Some("5")).is_err());
+    }
+
+    #[test]
+    fn clients_behind_the_forgotten_tombstones_reset() {
+        let at = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let mut log = ChangeLog::with_epoch("a".to_string());
+        for n in 0..=MAX_TOMBSTONES {
+            let infringement = infringement(&format!("SN-{n}"), at);
+            log.upsert(&infringement);
+            log.delete(&infringement);
+        }
+        assert_eq!(log.changes.len(), MAX_TOMBSTONES);
+        assert!(log.since(Some("a:1")).unwrap().reset);
+        assert!(
+            !log.since(Some(&log.format_cursor(log.horizon)))
+                .unwrap()
+                .reset
+        );
+    }
+
+    #[test]
+    fn every_start_has_its_own_epoch() {
+        let log = ChangeLog::default();
+        assert!(!log.epoch.is_empty());
+        assert!(log.since(None).unwrap().next_cursor.starts_with(&log.epoch));
+    }
+}
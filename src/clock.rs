@@ -0,0 +1,75 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::features::replay::{get_replay_status, ReplayStatus};
+
+/// A source of the current time.
+///
+/// Everything in the infringement pipeline asks a [Clock] for the time instead of calling
+/// [Utc::now] directly, so replays and tests can decide what "now" means.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+    /// Called with the timestamp of every drone snapshot that is processed
+    fn observe_snapshot(&self, _snapshot_time: DateTime<Utc>) {}
+}
+
+/// Wall-clock time, used when talking to the live api
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Follows the recorded time of the snapshot that is currently being replayed
+#[derive(Debug, Default)]
+pub struct ReplayClock {
+    snapshot_time: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> DateTime<Utc> {
+        // Before the first snapshot has been replayed there is no recorded time to report
+        self.snapshot_time.lock().unwrap().unwrap_or_else(Utc::now)
+    }
+    fn observe_snapshot(&self, snapshot_time: DateTime<Utc>) {
+        *self.snapshot_time.lock().unwrap() = Some(snapshot_time);
+    }
+}
+
+/// A clock that only moves when told to
+#[derive(Debug)]
+pub struct ManualClock {
+    time: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(time: DateTime<Utc>) -> Self {
+        Self {
+            time: Mutex::new(time),
+        }
+    }
+    pub fn set(&self, time: DateTime<Utc>) {
+        *self.time.lock().unwrap() = time;
+    }
+    pub fn advance(&self, duration: chrono::Duration) {
+        *self.time.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.time.lock().unwrap()
+    }
+}
+
+/// Picks the clock matching the current [ReplayStatus]
+pub fn default_clock() -> Arc<dyn Clock> {
+    match get_replay_status() {
+        ReplayStatus::Replaying => Arc::new(ReplayClock::default()),
+        _ => Arc::new(SystemClock),
+    }
+}
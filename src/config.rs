@@ -1,12 +1,245 @@
 use std::time::Duration;
 
-use crate::reaktor::drones::Drone;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::{reaktor::drones::Drone, schedule::Schedule};
 
 pub const NDZ_CENTER_X: f64 = 250000.0;
 pub const NDZ_CENTER_Y: f64 = 250000.0;
 pub const NDZ_MIN_ALLOWED_DISTANCE: f64 = 100_000.0;
-pub fn get_drone_distance_to_ndz(drone: &Drone) -> f64 {
-    ((drone.position_x - NDZ_CENTER_X).powi(2) + (drone.position_y - NDZ_CENTER_Y).powi(2)).sqrt()
+
+/// How close a drone got to a zone, from least to most severe
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Apiv2Schema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Advisory,
+    Warning,
+    Critical,
+}
+
+impl Tier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tier::Advisory => "advisory",
+            Tier::Warning => "warning",
+            Tier::Critical => "critical",
+        }
+    }
+}
+
+/// A circle around the zone center, drones inside it have reached the tier
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct Ring {
+    pub tier: Tier,
+    pub radius: f64,
+}
+
+/// Advisory at 1.5 times the zone radius, warning at the zone border and critical at half
+/// the radius, for the no-drone-zone 150 m, 100 m and 50 m
+fn default_rings(radius: f64) -> Vec<Ring> {
+    vec![
+        Ring {
+            tier: Tier::Advisory,
+            radius: radius * 1.5,
+        },
+        Ring {
+            tier: Tier::Warning,
+            radius,
+        },
+        Ring {
+            tier: Tier::Critical,
+            radius: radius / 2.0,
+        },
+    ]
+}
+
+/// A circular area drones are not allowed to enter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct Zone {
+    pub id: String,
+    pub center_x: f64,
+    pub center_y: f64,
+    /// Drones closer than this are inside the zone
+    pub radius: f64,
+    /// Severity rings, derived from the radius if left out.
+    /// Rings outside the zone only describe drones approaching it
+    #[serde(default)]
+    pub rings: Vec<Ring>,
+    /// When the zone is active, always if left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+}
+impl Zone {
+    pub fn distance_to(&self, drone: &Drone) -> f64 {
+        ((drone.position_x - self.center_x).powi(2) + (drone.position_y - self.center_y).powi(2))
+            .sqrt()
+    }
+
+    /// The worst tier of the rings a drone at this distance is inside of
+    pub fn tier_at(&self, distance: f64) -> Option<Tier> {
+        self.rings
+            .iter()
+            .filter(|ring| distance < ring.radius)
+            .map(|ring| ring.tier)
+            .max()
+    }
+
+    /// Whether a drone at this distance is inside the zone and infringes it,
+    /// rings outside the zone only describe drones approaching it
+    pub fn contains(&self, distance: f64) -> bool {
+        distance < self.radius
+    }
+
+    pub fn is_active(&self, time: DateTime<Utc>) -> bool {
+        self.schedule
+            .as_ref()
+            .is_none_or(|schedule| schedule.is_active(time))
+    }
+
+    /// Radius of the outermost ring
+    pub fn outer_radius(&self) -> f64 {
+        self.rings
+            .iter()
+            .map(|ring| ring.radius)
+            .fold(self.radius, f64::max)
+    }
+}
+
+lazy_static! {
+    static ref ZONES: Vec<Zone> = load_zones().unwrap_or_else(|e| panic!("{e:#}"));
+}
+
+/// Load the zones, so an invalid zone file stops the server at startup
+pub fn init() -> Result<()> {
+    load_zones()?;
+    lazy_static::initialize(&ZONES);
+    Ok(())
+}
+
+/// Read from the json file given with --zones or BIRDNEST_ZONES,
+/// defaults to the no-drone-zone around the nest
+fn load_zones() -> Result<Vec<Zone>> {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args
+        .iter()
+        .position(|a| a == "--zones")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("BIRDNEST_ZONES").ok());
+    let Some(path) = path else {
+        return Ok(vec![Zone {
+            id: "ndz".to_string(),
+            center_x: NDZ_CENTER_X,
+            center_y: NDZ_CENTER_Y,
+            radius: NDZ_MIN_ALLOWED_DISTANCE,
+            rings: default_rings(NDZ_MIN_ALLOWED_DISTANCE),
+            schedule: None,
+        }]);
+    };
+    let json = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to load zones from {path}"))?;
+    let mut zones: Vec<Zone> =
+        serde_json::from_str(&json).with_context(|| format!("Failed to load zones from {path}"))?;
+    for zone in zones.iter_mut() {
+        if zone.rings.is_empty() {
+            zone.rings = default_rings(zone.radius);
+        }
+        // Every drone inside the zone needs a tier
+        if zone.rings.iter().all(|ring| ring.radius < zone.radius) {
+            bail!("The rings of zone {} do not reach its border", zone.id);
+        }
+        if let Some(schedule) = &zone.schedule {
+            schedule
+                .validate()
+                .with_context(|| format!("Invalid schedule for zone {}", zone.id))?;
+        }
+    }
+    Ok(zones)
+}
+
+pub fn active_zones(time: DateTime<Utc>) -> Vec<Zone> {
+    ZONES
+        .iter()
+        .filter(|zone| zone.is_active(time))
+        .cloned()
+        .collect()
+}
+
+/// Every zone drones are checked against
+pub fn zones() -> Vec<Zone> {
+    ZONES.clone()
 }
 
 pub const INFRINGEMENT_DURATION: Duration = Duration::from_secs(600); // 10 minutes
+
+/// Weights of the severity score components, they add up to 100
+pub const SEVERITY_PROXIMITY_WEIGHT: f64 = 60.0;
+pub const SEVERITY_DWELL_WEIGHT: f64 = 25.0;
+pub const SEVERITY_ALTITUDE_WEIGHT: f64 = 15.0;
+/// Drones that stay this long get the full dwell score
+pub const SEVERITY_MAX_DWELL_SECS: f64 = 300.0;
+/// Drones at or above this altitude get no altitude score, in sensor units
+pub const SEVERITY_MAX_ALTITUDE: f64 = 10_000.0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(radius: f64) -> Zone {
+        Zone {
+            id: "test".to_string(),
+            center_x: 0.0,
+            center_y: 0.0,
+            radius,
+            rings: default_rings(radius),
+            schedule: None,
+        }
+    }
+
+    #[test]
+    fn default_rings_follow_the_radius() {
+        let zone = zone(40_000.0);
+        assert_eq!(zone.tier_at(59_000.0), Some(Tier::Advisory));
+        assert_eq!(zone.tier_at(39_000.0), Some(Tier::Warning));
+        assert_eq!(zone.tier_at(19_000.0), Some(Tier::Critical));
+        assert_eq!(zone.tier_at(61_000.0), None);
+        assert_eq!(zone.outer_radius(), 60_000.0);
+    }
+
+    #[test]
+    fn the_worst_ring_wins() {
+        // Rings in any order, a critical ring wider than the warning ring
+        let zone = Zone {
+            rings: vec![
+                Ring {
+                    tier: Tier::Critical,
+                    radius: 30_000.0,
+                },
+                Ring {
+                    tier: Tier::Warning,
+                    radius: 20_000.0,
+                },
+            ],
+            ..zone(20_000.0)
+        };
+        assert_eq!(zone.tier_at(10_000.0), Some(Tier::Critical));
+        assert_eq!(zone.tier_at(25_000.0), Some(Tier::Critical));
+        // The ring border is outside of the ring
+        assert_eq!(zone.tier_at(30_000.0), None);
+        assert_eq!(zone.outer_radius(), 30_000.0);
+    }
+
+    #[test]
+    fn only_drones_inside_the_zone_infringe() {
+        let zone = zone(NDZ_MIN_ALLOWED_DISTANCE);
+        assert!(!zone.contains(140_000.0));
+        assert!(!zone.contains(100_000.0));
+        assert!(zone.contains(99_000.0));
+        assert!(zone.contains(10_000.0));
+    }
+}
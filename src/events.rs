@@ -0,0 +1,301 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::debug;
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{active_zones, INFRINGEMENT_DURATION},
+    reaktor::drones::DronesDocument,
+};
+
+/// How many events are kept in memory
+const MAX_EVENTS: usize = 10_000;
+
+lazy_static! {
+    /// Zone entries and exits detected by [crate::record_infringements]
+    pub static ref EVENTS: Mutex<EventLog> = Mutex::new(EventLog::default());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    /// The drone flew into the zone
+    Enter,
+    /// The drone left the zone or disappeared from the sensor
+    Exit,
+}
+
+#[derive(Serialize, Debug, Clone, Apiv2Schema)]
+pub struct ZoneEvent {
+    /// Increases with every event, use as `since` to get newer events
+    pub id: u64,
+    pub kind: EventKind,
+    pub zone: String,
+    pub drone_serial_number: String,
+    /// Snapshot time the entry or exit was detected at
+    pub at: DateTime<Utc>,
+    /// Counts the visits of this drone to this zone, re-entries get a new number
+    pub visit: u32,
+    /// How long the visit lasted, only for exits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dwell_secs: Option<f64>,
+    /// Position when the event was detected, missing when the drone disappeared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<f64>,
+}
+
+/// A drone that is currently inside a zone
+#[derive(Debug, Clone)]
+struct Visit {
+    number: u32,
+    entered_at: DateTime<Utc>,
+}
+
+/// Visit counts of a drone in a zone, forgotten once the drone has been away for
+/// [INFRINGEMENT_DURATION]
+#[derive(Debug, Clone)]
+struct VisitCount {
+    visits: u32,
+    last_exit: DateTime<Utc>,
+}
+
+type VisitKey = (String, String);
+
+#[derive(Debug, Default)]
+pub struct EventLog {
+    next_id: u64,
+    events: VecDeque<ZoneEvent>,
+    active: HashMap<VisitKey, Visit>,
+    counts: HashMap<VisitKey, VisitCount>,
+    /// Replay run and time of the newest snapshot observed
+    last_snapshot: Option<(u64, DateTime<Utc>)>,
+}
+
+impl EventLog {
+    fn push(&mut self, mut event: ZoneEvent) {
+        self.next_id += 1;
+        event.id = self.next_id;
+        debug!(
+            "Drone {} {:?} zone {} (visit {})",
+            event.drone_serial_number, event.kind, event.zone, event.visit
+        );
+        self.events.push_back(event);
+        while self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+    }
+
+    /// Compare a snapshot against the previous one, producing an event for every
+    /// drone that entered or left a zone.
+    /// Snapshots are recorded concurrently, so they can arrive out of order
+    pub fn observe(&mut self, doc: &DronesDocument, at: DateTime<Utc>) {
+        let run = doc.replay_run.unwrap_or_default();
+        match self.last_snapshot {
+            // Left over from a previous run of the replay
+            Some((last_run, _)) if run < last_run => {
+                debug!("Ignoring a snapshot from an earlier replay run");
+                return;
+            }
+            // A replay started over, the visits from the previous loop can not be finished
+            Some((last_run, _)) if run > last_run => {
+                debug!("The replay started over, forgetting active visits");
+                self.active.clear();
+                self.counts.clear();
+            }
+            // The same snapshot can be recorded more than once,
+            // and an older one can finish after a newer one
+            Some((_, last)) if at <= last => {
+                debug!("Ignoring a snapshot that is not newer than the last one");
+                return;
+            }
+            _ => {}
+        }
+        self.last_snapshot = Some((run, at));
+
+        let mut inside = HashMap::new();
+        for zone in active_zones(at) {
+            for drone in &doc.capture.drone {
+                if zone.distance_to(drone) < zone.radius {
+                    inside.insert(
+                        (zone.id.clone(), drone.serial_number.clone()),
+                        (drone.position_x, drone.position_y),
+                    );
+                }
+            }
+        }
+        let positions: HashMap<_, _> = doc
+            .capture
+            .drone
+            .iter()
+            .map(|d| (d.serial_number.as_str(), (d.position_x, d.position_y)))
+            .collect();
+
+        let mut left: Vec<_> = self
+            .active
+            .keys()
+            .filter(|key| !inside.contains_key(*key))
+            .cloned()
+            .collect();
+        left.sort();
+        for key in left {
+            let visit = self.active.remove(&key).expect("Key was just listed");
+            let position = positions.get(key.1.as_str());
+            self.counts.insert(
+                key.clone(),
+                VisitCount {
+                    visits: visit.number,
+                    last_exit: at,
+                },
+            );
+            self.push(ZoneEvent {
+                id: 0,
+                kind: EventKind::Exit,
+                zone: key.0,
+                drone_serial_number: key.1,
+                at,
+                visit: visit.number,
+                dwell_secs: Some((at - visit.entered_at).num_milliseconds() as f64 / 1000.0),
+                x: position.map(|p| p.0),
+                y: position.map(|p| p.1),
+            });
+        }
+
+        let mut entered: Vec<_> = inside
+            .into_iter()
+            .filter(|(key, _)| !self.active.contains_key(key))
+            .collect();
+        entered.sort_by(|a, b| a.0.cmp(&b.0));
+        for (key, (x, y)) in entered {
+            let number = self.counts.get(&key).map_or(0, |c| c.visits) + 1;
+            self.active.insert(
+                key.clone(),
+                Visit {
+                    number,
+                    entered_at: at,
+                },
+            );
+            self.push(ZoneEvent {
+                id: 0,
+                kind: EventKind::Enter,
+                zone: key.0,
+                drone_serial_number: key.1,
+                at,
+                visit: number,
+                dwell_secs: None,
+                x: Some(x),
+                y: Some(y),
+            });
+        }
+
+        let forget_before = at - chrono::Duration::from_std(INFRINGEMENT_DURATION).unwrap();
+        self.counts
+            .retain(|key, count| self.active.contains_key(key) || count.last_exit >= forget_before);
+    }
+
+    /// Events after `since`, oldest first
+    pub fn since(&self, since: u64) -> impl Iterator<Item = &ZoneEvent> {
+        self.events.iter().filter(move |e| e.id > since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+    use crate::config::NDZ_CENTER_X;
+    use crate::test_fixtures;
+
+    /// A snapshot of drones at the given distances east of the zone center
+    fn snapshot(drones: &[(&str, f64)], replay_run: Option<u64>) -> DronesDocument {
+        DronesDocument {
+            replay_run,
+            ..test_fixtures::snapshot("", drones)
+        }
+    }
+
+    fn kinds(log: &EventLog) -> Vec<(EventKind, &str, u32)> {
+        log.since(0)
+            .map(|e| (e.kind, e.drone_serial_number.as_str(), e.visit))
+            .collect()
+    }
+
+    #[test]
+    fn entries_and_exits_are_detected() {
+        let start = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let mut log = EventLog::default();
+        log.observe(&snapshot(&[("SN-1", 0.0)], None), start);
+        log.observe(
+            &snapshot(&[("SN-1", 500_000.0)], None),
+            start + Duration::seconds(2),
+        );
+        log.observe(
+            &snapshot(&[("SN-1", 0.0)], None),
+            start + Duration::seconds(4),
+        );
+        assert_eq!(
+            kinds(&log),
+            [
+                (EventKind::Enter, "SN-1", 1),
+                (EventKind::Exit, "SN-1", 1),
+                (EventKind::Enter, "SN-1", 2)
+            ]
+        );
+        let exit = log.since(1).next().unwrap();
+        assert_eq!(exit.dwell_secs, Some(2.0));
+        assert_eq!(exit.x, Some(NDZ_CENTER_X + 500_000.0));
+    }
+
+    #[test]
+    fn stale_snapshots_are_ignored() {
+        let start = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let mut log = EventLog::default();
+        log.observe(&snapshot(&[("SN-1", 0.0)], None), start);
+        log.observe(
+            &snapshot(&[("SN-1", 0.0)], None),
+            start + Duration::seconds(4),
+        );
+        // Finished after the newer snapshot, the drone was not there yet
+        log.observe(&snapshot(&[], None), start + Duration::seconds(2));
+        // Recorded twice
+        log.observe(&snapshot(&[], None), start + Duration::seconds(4));
+        log.observe(
+            &snapshot(&[("SN-1", 0.0)], None),
+            start + Duration::seconds(6),
+        );
+        assert_eq!(kinds(&log), [(EventKind::Enter, "SN-1", 1)]);
+        assert_eq!(log.active.len(), 1);
+    }
+
+    #[test]
+    fn replays_starting_over_forget_active_visits() {
+        let start = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let mut log = EventLog::default();
+        log.observe(&snapshot(&[("SN-1", 0.0)], Some(0)), start);
+        log.observe(&snapshot(&[], Some(0)), start + Duration::seconds(2));
+        log.observe(
+            &snapshot(&[("SN-1", 0.0)], Some(0)),
+            start + Duration::seconds(4),
+        );
+        // Back at the first snapshot
+        log.observe(&snapshot(&[("SN-1", 0.0)], Some(1)), start);
+        // The last snapshot of the previous run finishing late
+        log.observe(&snapshot(&[], Some(0)), start + Duration::seconds(6));
+        assert_eq!(
+            kinds(&log),
+            [
+                (EventKind::Enter, "SN-1", 1),
+                (EventKind::Exit, "SN-1", 1),
+                (EventKind::Enter, "SN-1", 2),
+                (EventKind::Enter, "SN-1", 1)
+            ]
+        );
+        assert_eq!(log.last_snapshot, Some((1, start)));
+    }
+}
@@ -0,0 +1,112 @@
+use std::f64::consts::PI;
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::{
+    cache::{INFRINGEMENTS, LATEST_DRONE_SNAPSHOT},
+    config::{zones, Zone},
+};
+
+/// How many corners the circular zone polygons have
+const ZONE_POLYGON_CORNERS: usize = 64;
+
+/// A circle as a closed polygon ring
+fn circle(center_x: f64, center_y: f64, radius: f64) -> Value {
+    let ring: Vec<[f64; 2]> = (0..=ZONE_POLYGON_CORNERS)
+        .map(|i| {
+            let angle = 2.0 * PI * (i % ZONE_POLYGON_CORNERS) as f64 / ZONE_POLYGON_CORNERS as f64;
+            [
+                center_x + radius * angle.cos(),
+                center_y + radius * angle.sin(),
+            ]
+        })
+        .collect();
+    json!({ "type": "Polygon", "coordinates": [ring] })
+}
+
+/// A zone and each of its severity rings as polygons, the no-drone-zone keeps the kind "ndz"
+fn zone_polygons(zone: &Zone, time: DateTime<Utc>) -> Vec<Value> {
+    let mut features = vec![json!({
+        "type": "Feature",
+        "geometry": circle(zone.center_x, zone.center_y, zone.radius),
+        "properties": {
+            "kind": if zone.id == "ndz" { "ndz" } else { "zone" },
+            "zone": zone.id,
+            "center_x": zone.center_x,
+            "center_y": zone.center_y,
+            "radius": zone.radius,
+            "active": zone.is_active(time),
+        },
+    })];
+    for ring in &zone.rings {
+        features.push(json!({
+            "type": "Feature",
+            "geometry": circle(zone.center_x, zone.center_y, ring.radius),
+            "properties": {
+                "kind": "ring",
+                "zone": zone.id,
+                "tier": ring.tier,
+                "radius": ring.radius,
+            },
+        }));
+    }
+    features
+}
+
+/// The latest snapshot as a GeoJSON FeatureCollection: a point per drone, the zones and their rings.
+/// Coordinates are in the same units as the sensor reports them
+pub async fn airspace_geojson() -> Value {
+    let zones = zones();
+    let snapshot = LATEST_DRONE_SNAPSHOT.lock().await.clone();
+    let time = snapshot
+        .as_ref()
+        .and_then(|doc| doc.snapshot_time())
+        .unwrap_or_else(Utc::now);
+    let mut features: Vec<Value> = zones
+        .iter()
+        .flat_map(|zone| zone_polygons(zone, time))
+        .collect();
+    let infringements = INFRINGEMENTS.lock().await;
+    let mut snapshot_timestamp = None;
+    if let Some(doc) = snapshot {
+        snapshot_timestamp = Some(doc.capture.snapshot_timestamp);
+        for drone in doc.capture.drone {
+            // The zone with the closest border
+            let closest = zones
+                .iter()
+                .map(|zone| (zone, zone.distance_to(&drone)))
+                .min_by(|(a, a_distance), (b, b_distance)| {
+                    (a_distance - a.radius).total_cmp(&(b_distance - b.radius))
+                });
+            let mut properties = serde_json::to_value(&drone).expect("Drones always serialize");
+            properties["kind"] = json!("drone");
+            properties["zone"] = json!(closest.map(|(zone, _)| &zone.id));
+            properties["distance_to_ndz"] = json!(closest.map(|(_, distance)| distance));
+            properties["inside_ndz"] = json!(zones
+                .iter()
+                .any(|zone| zone.contains(zone.distance_to(&drone))));
+            // Drones that have entered the zone during the last 10 minutes
+            properties["recently_infringed"] =
+                json!(infringements.contains_key(&drone.serial_number));
+            properties["tier"] = json!(zones
+                .iter()
+                .filter_map(|zone| zone.tier_at(zone.distance_to(&drone)))
+                .max());
+            features.push(json!({
+                "type": "Feature",
+                "id": drone.serial_number,
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [drone.position_x, drone.position_y],
+                },
+                "properties": properties,
+            }));
+        }
+    }
+    json!({
+        "type": "FeatureCollection",
+        "snapshot_timestamp": snapshot_timestamp,
+        "features": features,
+    })
+}
@@ -0,0 +1,573 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Tier,
+    features::{email, history::read_history, pilots, review, webhooks},
+    schedule::Schedule,
+    Infringement, InfringementStatus,
+};
+
+/// Alerts kept for /alerts, the oldest are dropped first
+const ALERTS_KEPT: usize = 1000;
+
+lazy_static! {
+    static ref RULES: Vec<AlertRule> =
+        load_rules().unwrap_or_else(|e| panic!("Failed to load alert rules: {e}"));
+    static ref STATE: Mutex<AlertState> = Mutex::new(AlertState::default());
+}
+
+/// What has to happen for a rule to fire
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Apiv2Schema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    /// A drone closer than `distance` to a zone center, in the units of the sensor (mm)
+    DistanceBelow {
+        distance: f64,
+    },
+    /// An infringement that reached the tier or a worse one
+    TierAtLeast {
+        tier: Tier,
+    },
+    SeverityAtLeast {
+        severity: f64,
+    },
+    /// A pilot with `count` or more infringements that started within the last `within_hours`
+    PilotOffenses {
+        count: usize,
+        within_hours: u64,
+    },
+    /// More than `more_than` drones infringing at once
+    ActiveInfringements {
+        more_than: usize,
+    },
+}
+
+/// What makes two alerts of a rule the same alert for the cooldown
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupBy {
+    Drone,
+    Pilot,
+    Zone,
+    /// The rule fires once per cooldown, whatever caused it
+    Rule,
+}
+
+/// Where fired alerts are sent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertChannel {
+    /// Webhooks subscribed to alert.fired
+    Webhook,
+    /// The recipients in BIRDNEST_SMTP_TO
+    Email,
+}
+
+fn all_channels() -> Vec<AlertChannel> {
+    vec![AlertChannel::Webhook, AlertChannel::Email]
+}
+
+fn default_cooldown() -> u64 {
+    600
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct AlertRule {
+    #[openapi(example = "close-drones")]
+    pub id: String,
+    pub condition: Condition,
+    /// Defaults to drone, pilot for pilot_offenses and rule for active_infringements
+    pub dedup_by: Option<DedupBy>,
+    /// Alerts with the same dedup key are only sent once within this many seconds
+    #[serde(default = "default_cooldown")]
+    pub cooldown_secs: u64,
+    /// Alerts are recorded but not sent while the schedule is active
+    pub quiet_hours: Option<Schedule>,
+    /// Every channel if left out
+    #[serde(default = "all_channels")]
+    pub channels: Vec<AlertChannel>,
+}
+
+impl AlertRule {
+    fn dedup_by(&self) -> DedupBy {
+        self.dedup_by.unwrap_or(match self.condition {
+            Condition::PilotOffenses { .. } => DedupBy::Pilot,
+            Condition::ActiveInfringements { .. } => DedupBy::Rule,
+            _ => DedupBy::Drone,
+        })
+    }
+
+    fn dedup_key(&self, infringement: Option<&Infringement>) -> String {
+        let subject = match (self.dedup_by(), infringement) {
+            (DedupBy::Drone, Some(i)) => i.drone_serial_number.clone(),
+            (DedupBy::Pilot, Some(i)) => i
+                .pilot
+                .as_ref()
+                .map(|p| p.pilot_id.clone())
+                .unwrap_or_else(|| i.drone_serial_number.clone()),
+            (DedupBy::Zone, Some(i)) => i.zone.clone(),
+            _ => String::new(),
+        };
+        format!("{}:{subject}", self.id)
+    }
+}
+
+/// Read from the json file given with --alert-rules or BIRDNEST_ALERT_RULES
+fn load_rules() -> Result<Vec<AlertRule>> {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args
+        .iter()
+        .position(|a| a == "--alert-rules")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("BIRDNEST_ALERT_RULES").ok());
+    let Some(path) = path else {
+        return Ok(vec![]);
+    };
+    let json = std::fs::read_to_string(&path).with_context(|| format!("Can't read {path}"))?;
+    let rules: Vec<AlertRule> = serde_json::from_str(&json).with_context(|| path.clone())?;
+    let mut ids = BTreeSet::new();
+    for rule in &rules {
+        if !ids.insert(&rule.id) {
+            bail!("Rule id {} is used more than once", rule.id);
+        }
+        if let Some(quiet_hours) = &rule.quiet_hours {
+            quiet_hours
+                .validate()
+                .with_context(|| format!("Invalid quiet hours for rule {}", rule.id))?;
+        }
+    }
+    info!("Loaded {} alert rules from {path}", rules.len());
+    Ok(rules)
+}
+
+/// Load the rules, so an invalid rule file stops the server at startup
+pub fn init() -> Result<()> {
+    load_rules()?;
+    lazy_static::initialize(&RULES);
+    Ok(())
+}
+
+pub fn rules() -> Vec<AlertRule> {
+    RULES.clone()
+}
+
+/// A rule that fired
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct Alert {
+    pub id: u64,
+    pub rule_id: String,
+    /// Alerts with the same key are held back for the cooldown of the rule
+    pub dedup_key: String,
+    /// Snapshot time the rule fired at
+    pub fired_at: DateTime<Utc>,
+    #[openapi(example = "Drone SN-fqdF8EI8jF is 28.4 m from the center of ndz")]
+    pub message: String,
+    /// The worst tier of the infringements
+    pub tier: Option<Tier>,
+    /// Not sent because of the quiet hours of the rule
+    pub suppressed: bool,
+    /// The infringements that caused the alert
+    pub infringements: Vec<Infringement>,
+}
+
+#[derive(Default)]
+struct AlertState {
+    next_id: u64,
+    /// When each dedup key last fired
+    fired: HashMap<String, DateTime<Utc>>,
+    alerts: VecDeque<Alert>,
+}
+
+impl AlertState {
+    /// Whether the key is still cooling down. Time going backwards, like a replay
+    /// starting over, ends the cooldown
+    fn cooling_down(&self, key: &str, cooldown: Duration, now: DateTime<Utc>) -> bool {
+        self.fired
+            .get(key)
+            .is_some_and(|at| *at <= now && now - *at < cooldown)
+    }
+
+    /// Record an alert of the rule unless its dedup key is cooling down. The alert is
+    /// suppressed during the quiet hours of the rule, only sent alerts start the cooldown
+    fn fire(
+        &mut self,
+        rule: &AlertRule,
+        message: String,
+        infringements: Vec<Infringement>,
+        now: DateTime<Utc>,
+    ) -> Option<Alert> {
+        let key = rule.dedup_key(infringements.first());
+        if self.cooling_down(&key, Duration::seconds(rule.cooldown_secs as i64), now) {
+            return None;
+        }
+        let suppressed = rule
+            .quiet_hours
+            .as_ref()
+            .is_some_and(|schedule| schedule.is_active(now));
+        if !suppressed {
+            self.fired.insert(key.clone(), now);
+        }
+        self.next_id += 1;
+        let alert = Alert {
+            id: self.next_id,
+            rule_id: rule.id.clone(),
+            dedup_key: key,
+            fired_at: now,
+            message,
+            tier: infringements.iter().map(|i| i.tier).max(),
+            suppressed,
+            infringements,
+        };
+        self.alerts.push_back(alert.clone());
+        if self.alerts.len() > ALERTS_KEPT {
+            self.alerts.pop_front();
+        }
+        Some(alert)
+    }
+}
+
+fn meters(distance: f64) -> f64 {
+    distance / 1000.0
+}
+
+/// The message and infringements of a rule that matches, one per drone for the per
+/// infringement conditions
+fn matches(
+    rule: &AlertRule,
+    touched: &[Infringement],
+    created: &[Infringement],
+    active: &[Infringement],
+    all: &[Infringement],
+    now: DateTime<Utc>,
+) -> Vec<(String, Vec<Infringement>)> {
+    let per_drone = |test: &dyn Fn(&Infringement) -> Option<String>| {
+        touched
+            .iter()
+            .filter_map(|i| test(i).map(|message| (message, vec![i.clone()])))
+            .collect()
+    };
+    match &rule.condition {
+        Condition::DistanceBelow { distance } => per_drone(&|i| {
+            (i.distance < *distance).then(|| {
+                format!(
+                    "Drone {} is {:.1} m from the center of {}",
+                    i.drone_serial_number,
+                    meters(i.distance),
+                    i.zone
+                )
+            })
+        }),
+        Condition::TierAtLeast { tier } => per_drone(&|i| {
+            (i.tier >= *tier).then(|| {
+                format!(
+                    "Drone {} reached the {} tier of {}",
+                    i.drone_serial_number,
+                    i.tier.as_str(),
+                    i.zone
+                )
+            })
+        }),
+        Condition::SeverityAtLeast { severity } => per_drone(&|i| {
+            (i.severity >= *severity).then(|| {
+                format!(
+                    "Drone {} has a severity of {:.0} in {}",
+                    i.drone_serial_number, i.severity, i.zone
+                )
+            })
+        }),
+        Condition::PilotOffenses {
+            count,
+            within_hours,
+        } => {
+            // Offense counts only change when an infringement starts
+            let pilots: BTreeSet<_> = created
+                .iter()
+                .filter_map(|i| i.pilot.as_ref().map(|p| p.pilot_id.clone()))
+                .collect();
+            if pilots.is_empty() {
+                return vec![];
+            }
+            let since = now - Duration::hours(*within_hours as i64);
+            pilots
+                .into_iter()
+                .filter_map(|pilot_id| {
+                    let offenses: Vec<_> = pilots::offenses_of(&pilot_id, all.iter().cloned())
+                        .into_iter()
+                        .filter(|i| i.first_seen_at >= since)
+                        .collect();
+                    (offenses.len() >= *count).then(|| {
+                        (
+                            format!(
+                                "Pilot {pilot_id} has {} infringements in the last {within_hours} h",
+                                offenses.len()
+                            ),
+                            offenses,
+                        )
+                    })
+                })
+                .collect()
+        }
+        Condition::ActiveInfringements { more_than } => (active.len() > *more_than
+            && !touched.is_empty())
+        .then(|| {
+            (
+                format!("{} drones are infringing at once", active.len()),
+                active.to_vec(),
+            )
+        })
+        .into_iter()
+        .collect(),
+    }
+}
+
+/// Evaluate every rule after a snapshot has been recorded. `touched` are the infringements
+/// the snapshot updated, `created` the ones it started or that stopped being authorized
+/// and `active` every infringement in the cache. Reads the history for pilot rules, so it
+/// blocks
+pub fn evaluate(
+    touched: &[Infringement],
+    created: &[Infringement],
+    active: &[Infringement],
+    now: DateTime<Utc>,
+) {
+    if RULES.is_empty() {
+        return;
+    }
+    let infringing = |list: &[Infringement]| -> Vec<Infringement> {
+        review::with_reviews(list.to_vec())
+            .into_iter()
+            .filter(|i| i.status == InfringementStatus::Infringing)
+            .filter(|i| i.review.as_ref().map(|r| r.state) != Some(review::ReviewState::Dismissed))
+            .collect()
+    };
+    let touched: Vec<_> = infringing(touched)
+        .into_iter()
+        .filter(|i| {
+            touched
+                .iter()
+                .any(|t| t.drone_serial_number == i.drone_serial_number)
+        })
+        .collect();
+    let created: Vec<_> = touched
+        .iter()
+        .filter(|i| {
+            created
+                .iter()
+                .any(|c| c.drone_serial_number == i.drone_serial_number)
+        })
+        .cloned()
+        .collect();
+    // History is only read if a pilot rule needs it, before the state is locked.
+    // Dismissed infringements are left out of the offenses after deduplicating, so their
+    // archived copies don't count either
+    let needs_history = created.iter().any(|i| i.pilot.is_some())
+        && RULES
+            .iter()
+            .any(|r| matches!(r.condition, Condition::PilotOffenses { .. }));
+    let all: Vec<Infringement> = match needs_history {
+        true => {
+            let history = read_history()
+                .map_err(|e| warn!("Alert rules can't read the history: {e}"))
+                .into_iter()
+                .flatten();
+            pilots::deduplicate(review::with_reviews(active.to_vec()), history).collect()
+        }
+        false => vec![],
+    };
+    let active = infringing(active);
+
+    let mut fired = vec![];
+    {
+        let mut state = STATE.lock().unwrap();
+        for rule in RULES.iter() {
+            for (message, infringements) in matches(rule, &touched, &created, &active, &all, now) {
+                let Some(alert) = state.fire(rule, message, infringements, now) else {
+                    continue;
+                };
+                info!("Alert {}: {}", alert.rule_id, alert.message);
+                if !alert.suppressed {
+                    fired.push((alert, rule.channels.clone()));
+                }
+            }
+        }
+        // Keys that have cooled down are not needed anymore
+        let longest = RULES
+            .iter()
+            .map(|r| r.cooldown_secs)
+            .max()
+            .unwrap_or_default();
+        state
+            .fired
+            .retain(|_, at| *at > now || now - *at < Duration::seconds(longest as i64));
+    }
+    for (alert, channels) in fired {
+        for channel in channels {
+            match channel {
+                AlertChannel::Webhook => webhooks::notify_alert(&alert),
+                AlertChannel::Email => email::notify_alert(&alert),
+            }
+        }
+    }
+}
+
+/// Alerts newest first
+pub fn list_alerts(since: Option<u64>, rule_id: Option<&str>, limit: usize) -> Vec<Alert> {
+    STATE
+        .lock()
+        .unwrap()
+        .alerts
+        .iter()
+        .rev()
+        .filter(|a| since.is_none_or(|since| a.id > since))
+        .filter(|a| rule_id.is_none_or(|id| a.rule_id == id))
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_fixtures::{self, pilot};
+
+    fn rule(value: Value) -> AlertRule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn infringement(serial: &str, pilot_id: Option<&str>) -> Infringement {
+        Infringement {
+            pilot: pilot_id.map(pilot),
+            zone: "nest".to_string(),
+            ..test_fixtures::infringement(serial, utc("2023-06-30T12:00:00Z"))
+        }
+    }
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn dedup_keys_follow_the_rule() {
+        let with_pilot = infringement("SN-1", Some("P-1"));
+        let without_pilot = infringement("SN-2", None);
+        let close = rule(json!({
+            "id": "close",
+            "condition": { "kind": "distance_below", "distance": 50000.0 },
+        }));
+        assert_eq!(close.dedup_key(Some(&with_pilot)), "close:SN-1");
+        let offenders = rule(json!({
+            "id": "offenders",
+            "condition": { "kind": "pilot_offenses", "count": 3, "within_hours": 24 },
+        }));
+        assert_eq!(offenders.dedup_key(Some(&with_pilot)), "offenders:P-1");
+        // Drones without a known pilot fall back to the drone
+        assert_eq!(offenders.dedup_key(Some(&without_pilot)), "offenders:SN-2");
+        let crowded = rule(json!({
+            "id": "crowded",
+            "condition": { "kind": "active_infringements", "more_than": 5 },
+        }));
+        assert_eq!(crowded.dedup_key(Some(&with_pilot)), "crowded:");
+        let per_zone = rule(json!({
+            "id": "zone",
+            "condition": { "kind": "tier_at_least", "tier": "warning" },
+            "dedup_by": "zone",
+        }));
+        assert_eq!(per_zone.dedup_key(Some(&with_pilot)), "zone:nest");
+    }
+
+    #[test]
+    fn alerts_wait_for_the_cooldown() {
+        let rule = rule(json!({
+            "id": "close",
+            "condition": { "kind": "distance_below", "distance": 50000.0 },
+            "cooldown_secs": 60,
+        }));
+        let mut state = AlertState::default();
+        let fire = |state: &mut AlertState, serial: &str, at: &str| {
+            state
+                .fire(
+                    &rule,
+                    String::new(),
+                    vec![infringement(serial, None)],
+                    utc(at),
+                )
+                .is_some()
+        };
+        assert!(fire(&mut state, "SN-1", "2023-06-30T12:00:00Z"));
+        assert!(!fire(&mut state, "SN-1", "2023-06-30T12:00:59Z"));
+        // Another drone has its own key
+        assert!(fire(&mut state, "SN-2", "2023-06-30T12:00:30Z"));
+        assert!(fire(&mut state, "SN-1", "2023-06-30T12:01:00Z"));
+        // A replay starting over ends the cooldown
+        assert!(fire(&mut state, "SN-1", "2023-06-30T11:00:00Z"));
+        assert_eq!(state.alerts.len(), 4);
+    }
+
+    #[test]
+    fn quiet_hours_suppress_alerts() {
+        let rule = rule(json!({
+            "id": "close",
+            "condition": { "kind": "distance_below", "distance": 50000.0 },
+            "cooldown_secs": 0,
+            "quiet_hours": {
+                "time_zone": "UTC",
+                "windows": [{ "days": ["fri"], "start": "22:00", "end": "06:00" }]
+            },
+        }));
+        let mut state = AlertState::default();
+        let mut fire = |at: &str| {
+            state
+                .fire(
+                    &rule,
+                    String::new(),
+                    vec![infringement("SN-1", None)],
+                    utc(at),
+                )
+                .unwrap()
+        };
+        // 2023-06-30 is a Friday
+        assert!(!fire("2023-06-30T21:59:00Z").suppressed);
+        assert!(fire("2023-06-30T23:00:00Z").suppressed);
+        assert!(fire("2023-07-01T05:59:00Z").suppressed);
+        assert!(!fire("2023-07-01T06:00:00Z").suppressed);
+    }
+
+    #[test]
+    fn suppressed_alerts_do_not_start_the_cooldown() {
+        let rule = rule(json!({
+            "id": "close",
+            "condition": { "kind": "distance_below", "distance": 50000.0 },
+            "cooldown_secs": 600,
+            "quiet_hours": {
+                "time_zone": "UTC",
+                "windows": [{ "days": ["fri"], "start": "22:00", "end": "06:00" }]
+            },
+        }));
+        let mut state = AlertState::default();
+        let mut fire = |at: &str| {
+            state.fire(
+                &rule,
+                String::new(),
+                vec![infringement("SN-1", None)],
+                utc(at),
+            )
+        };
+        // 2023-06-30 is a Friday
+        assert!(fire("2023-06-30T23:00:00Z").unwrap().suppressed);
+        assert!(fire("2023-06-30T23:01:00Z").unwrap().suppressed);
+        // Sent right after the quiet hours, then cooling down
+        assert!(!fire("2023-07-01T06:00:00Z").unwrap().suppressed);
+        assert!(fire("2023-07-01T06:05:00Z").is_none());
+        assert!(fire("2023-07-01T06:10:00Z").is_some());
+    }
+}
@@ -0,0 +1,442 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::{authentication::Credentials, AsyncSmtpTransport},
+    AsyncTransport, Message, Tokio1Executor,
+};
+use log::{error, info, warn};
+use rustls_pki_types::ServerName;
+
+use crate::{
+    config::Tier,
+    features::{
+        alerts::Alert,
+        privacy::{redact_infringement, redact_infringements},
+        templates::{render, TemplateContext},
+        webhooks::WebhookEvent,
+    },
+    Infringement, InfringementStatus,
+};
+
+/// Emails waiting to be sent, the oldest are dropped if the server is unreachable for long
+const QUEUE_LIMIT: usize = 1000;
+
+lazy_static! {
+    /// Emails are only sent if BIRDNEST_SMTP_HOST is set
+    static ref CONFIG: Option<EmailConfig> = EmailConfig::from_env()
+        .unwrap_or_else(|e| panic!("Invalid email configuration: {e}"));
+    static ref TRANSPORT: Option<AsyncSmtpTransport<Tokio1Executor>> = CONFIG
+        .as_ref()
+        .map(|config| config.transport().unwrap_or_else(|e| panic!("{e:#}")));
+    static ref QUEUE: Mutex<Vec<Email>> = Mutex::new(vec![]);
+}
+
+/// Check the email options and the SMTP server, so an invalid configuration stops the server
+/// at startup
+pub fn init() -> Result<()> {
+    if let Some(config) = EmailConfig::from_env().context("Invalid email configuration")? {
+        config.transport()?;
+    }
+    lazy_static::initialize(&CONFIG);
+    lazy_static::initialize(&TRANSPORT);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SmtpTls {
+    /// Plain text, for local SMTP catchers
+    None,
+    /// Upgrade the connection with STARTTLS, port 587 by default
+    StartTls,
+    /// TLS from the start, port 465 by default
+    Tls,
+}
+
+struct EmailConfig {
+    host: String,
+    port: Option<u16>,
+    tls: SmtpTls,
+    credentials: Option<Credentials>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    /// Collect the emails into a single digest sent this often, every email is sent
+    /// on its own without it
+    digest: Option<Duration>,
+    /// Infringement events that are emailed, alerts are emailed when their rule says so
+    events: Vec<WebhookEvent>,
+    /// Also email about authorized drones, read from BIRDNEST_SMTP_INCLUDE_AUTHORIZED
+    include_authorized: bool,
+}
+
+impl EmailConfig {
+    fn from_env() -> Result<Option<Self>> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Read the options with `var`, empty values count as unset
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Option<Self>> {
+        let env = |name: &str| var(name).filter(|v| !v.is_empty());
+        let Some(host) = env("BIRDNEST_SMTP_HOST") else {
+            return Ok(None);
+        };
+        let port = env("BIRDNEST_SMTP_PORT")
+            .map(|port| port.parse().context("BIRDNEST_SMTP_PORT"))
+            .transpose()?;
+        let tls = match env("BIRDNEST_SMTP_TLS").as_deref() {
+            None | Some("starttls") => SmtpTls::StartTls,
+            Some("tls") => SmtpTls::Tls,
+            Some("none") => SmtpTls::None,
+            Some(other) => {
+                bail!("Unknown BIRDNEST_SMTP_TLS {other}, expected starttls, tls or none")
+            }
+        };
+        let credentials = match (env("BIRDNEST_SMTP_USERNAME"), env("BIRDNEST_SMTP_PASSWORD")) {
+            (Some(username), Some(password)) => Some(Credentials::new(username, password)),
+            (None, None) => None,
+            _ => bail!("Set both BIRDNEST_SMTP_USERNAME and BIRDNEST_SMTP_PASSWORD"),
+        };
+        let from = env("BIRDNEST_SMTP_FROM")
+            .context("BIRDNEST_SMTP_FROM is required")?
+            .parse()
+            .context("BIRDNEST_SMTP_FROM")?;
+        let to = env("BIRDNEST_SMTP_TO")
+            .context("BIRDNEST_SMTP_TO is required")?
+            .split(',')
+            .map(|to| {
+                to.trim()
+                    .parse()
+                    .with_context(|| format!("Invalid address {to}"))
+            })
+            .collect::<Result<Vec<Mailbox>>>()?;
+        let digest = env("BIRDNEST_SMTP_DIGEST_SECS")
+            .map(|secs| secs.parse().context("BIRDNEST_SMTP_DIGEST_SECS"))
+            .transpose()?
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+        let events = match env("BIRDNEST_SMTP_EVENTS") {
+            Some(events) => events
+                .split(',')
+                .map(|event| {
+                    serde_json::from_value(serde_json::Value::String(event.trim().to_string()))
+                        .with_context(|| format!("Unknown event {event}"))
+                })
+                .collect::<Result<_>>()?,
+            None => vec![WebhookEvent::InfringementCreated],
+        };
+        let include_authorized = match env("BIRDNEST_SMTP_INCLUDE_AUTHORIZED").as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => {
+                bail!("Invalid BIRDNEST_SMTP_INCLUDE_AUTHORIZED {other}, expected true or false")
+            }
+        };
+        Ok(Some(Self {
+            host,
+            port,
+            tls,
+            credentials,
+            from,
+            to,
+            digest,
+            events,
+            include_authorized,
+        }))
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+        let invalid = || format!("Invalid SMTP server {}", self.host);
+        ServerName::try_from(self.host.as_str()).with_context(invalid)?;
+        let mut builder = match self.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host),
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host)
+                .with_context(invalid)?,
+            SmtpTls::Tls => {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&self.host).with_context(invalid)?
+            }
+        };
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+        if let Some(credentials) = &self.credentials {
+            builder = builder.credentials(credentials.clone());
+        }
+        Ok(builder.timeout(Some(Duration::from_secs(30))).build())
+    }
+}
+
+/// A rendered email
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub subject: String,
+    pub body: String,
+}
+
+fn render_email(subject: &str, body: &str, context: &TemplateContext) -> Result<Email> {
+    Ok(Email {
+        // Templates usually end with a newline
+        subject: render(subject, context)?.trim().to_string(),
+        body: render(body, context)?,
+    })
+}
+
+/// Render email/subject and email/body, the pilot redacted with BIRDNEST_REDACT
+pub fn render_infringement(
+    event: WebhookEvent,
+    infringement: &Infringement,
+    previous_tier: Option<Tier>,
+) -> Result<Email> {
+    let mut infringement = infringement.clone();
+    redact_infringement(&mut infringement);
+    let context = TemplateContext::infringement(event, infringement, previous_tier);
+    render_email("email/subject", "email/body", &context)
+}
+
+/// Render email/alert_subject and email/alert_body, the pilots redacted with BIRDNEST_REDACT
+pub fn render_alert(alert: &Alert) -> Result<Email> {
+    let mut alert = alert.clone();
+    redact_infringements(&mut alert.infringements);
+    let context = TemplateContext::alert(alert);
+    render_email("email/alert_subject", "email/alert_body", &context)
+}
+
+fn enqueue(email: Email) {
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() >= QUEUE_LIMIT {
+        warn!("Email queue is full, dropping the oldest email");
+        queue.remove(0);
+    }
+    queue.push(email);
+}
+
+/// Queue an email about the infringement if infringement emails are on for the event,
+/// authorized drones only with BIRDNEST_SMTP_INCLUDE_AUTHORIZED
+pub fn notify(event: WebhookEvent, infringement: &Infringement, previous_tier: Option<Tier>) {
+    let Some(config) = CONFIG.as_ref() else {
+        return;
+    };
+    if config.events.contains(&event)
+        && match infringement.status {
+            InfringementStatus::Infringing => true,
+            InfringementStatus::Authorized => config.include_authorized,
+            InfringementStatus::Approaching => false,
+        }
+    {
+        match render_infringement(event, infringement, previous_tier) {
+            Ok(email) => enqueue(email),
+            Err(e) => error!("Failed to render an email: {e:#}"),
+        }
+    }
+}
+
+/// Queue an email about a fired alert
+pub fn notify_alert(alert: &Alert) {
+    if CONFIG.is_some() {
+        match render_alert(alert) {
+            Ok(email) => enqueue(email),
+            Err(e) => error!("Failed to render an email: {e:#}"),
+        }
+    }
+}
+
+/// Several emails as one
+fn digest(emails: &[Email]) -> Email {
+    let mut body = String::new();
+    for email in emails {
+        body.push_str(&format!("== {} ==\n\n{}\n", email.subject, email.body));
+    }
+    Email {
+        subject: match emails.len() {
+            1 => "1 drone notification".to_string(),
+            n => format!("{n} drone notifications"),
+        },
+        body,
+    }
+}
+
+async fn send(
+    config: &EmailConfig,
+    transport: &AsyncSmtpTransport<Tokio1Executor>,
+    email: &Email,
+) -> Result<()> {
+    let mut builder = Message::builder()
+        .from(config.from.clone())
+        .subject(format!("[Birdnest] {}", email.subject))
+        .header(ContentType::TEXT_PLAIN);
+    for to in &config.to {
+        builder = builder.to(to.clone());
+    }
+    transport.send(builder.body(email.body.clone())?).await?;
+    Ok(())
+}
+
+/// Send the queued emails, forever. Does nothing without BIRDNEST_SMTP_HOST
+pub async fn run_email() {
+    let (Some(config), Some(transport)) = (CONFIG.as_ref(), TRANSPORT.as_ref()) else {
+        return;
+    };
+    info!(
+        "Sending emails to {} recipients through {}",
+        config.to.len(),
+        config.host
+    );
+    loop {
+        tokio::time::sleep(config.digest.unwrap_or(Duration::from_secs(2))).await;
+        let queued = std::mem::take(&mut *QUEUE.lock().unwrap());
+        if queued.is_empty() {
+            continue;
+        }
+        let emails = match config.digest {
+            Some(_) => vec![digest(&queued)],
+            None => queued.clone(),
+        };
+        for (index, email) in emails.iter().enumerate() {
+            if let Err(e) = send(config, transport, email).await {
+                error!("Failed to send email \"{}\": {e}", email.subject);
+                // Retried with the next batch
+                let unsent = match config.digest {
+                    Some(_) => queued,
+                    None => queued[index..].to_vec(),
+                };
+                let mut queue = QUEUE.lock().unwrap();
+                let newer = std::mem::replace(&mut *queue, unsent);
+                queue.extend(newer);
+                let overflow = queue.len().saturating_sub(QUEUE_LIMIT);
+                queue.drain(..overflow);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::test_fixtures::{infringement, pilot};
+
+    fn config(vars: &[(&str, &str)]) -> Result<Option<EmailConfig>> {
+        let mut all: HashMap<&str, &str> = HashMap::from([
+            ("BIRDNEST_SMTP_HOST", "smtp.example.com"),
+            ("BIRDNEST_SMTP_FROM", "Birdnest <birdnest@example.com>"),
+            (
+                "BIRDNEST_SMTP_TO",
+                "ranger@example.com, Guard <guard@example.com>",
+            ),
+        ]);
+        all.extend(vars.iter().copied());
+        EmailConfig::from_vars(|name| all.get(name).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn emails_are_off_without_a_host() {
+        assert!(config(&[("BIRDNEST_SMTP_HOST", "")]).unwrap().is_none());
+    }
+
+    #[test]
+    fn tls_modes() {
+        let tls = |value| config(&[("BIRDNEST_SMTP_TLS", value)]).map(|c| c.unwrap().tls);
+        assert_eq!(tls("").unwrap(), SmtpTls::StartTls);
+        assert_eq!(tls("starttls").unwrap(), SmtpTls::StartTls);
+        assert_eq!(tls("tls").unwrap(), SmtpTls::Tls);
+        assert_eq!(tls("none").unwrap(), SmtpTls::None);
+        assert!(tls("ssl").is_err());
+    }
+
+    #[test]
+    fn credentials_are_given_together() {
+        let both = config(&[
+            ("BIRDNEST_SMTP_USERNAME", "birdnest"),
+            ("BIRDNEST_SMTP_PASSWORD", "secret"),
+        ]);
+        assert!(both.unwrap().unwrap().credentials.is_some());
+        assert!(config(&[]).unwrap().unwrap().credentials.is_none());
+        assert!(config(&[("BIRDNEST_SMTP_USERNAME", "birdnest")]).is_err());
+        assert!(config(&[("BIRDNEST_SMTP_PASSWORD", "secret")]).is_err());
+    }
+
+    #[test]
+    fn addresses_are_checked() {
+        let valid = config(&[]).unwrap().unwrap();
+        assert_eq!(valid.from.to_string(), "Birdnest <birdnest@example.com>");
+        assert_eq!(valid.to.len(), 2);
+        assert_eq!(valid.to[1].email.to_string(), "guard@example.com");
+
+        assert!(config(&[("BIRDNEST_SMTP_FROM", "")]).is_err());
+        assert!(config(&[("BIRDNEST_SMTP_FROM", "birdnest")]).is_err());
+        assert!(config(&[("BIRDNEST_SMTP_TO", "a@example.com,nobody")]).is_err());
+    }
+
+    #[test]
+    fn events_default_to_created_infringements() {
+        let events = |value| config(&[("BIRDNEST_SMTP_EVENTS", value)]).map(|c| c.unwrap().events);
+        assert_eq!(events("").unwrap(), [WebhookEvent::InfringementCreated]);
+        assert_eq!(
+            events("infringement.ended, alert.fired").unwrap(),
+            [WebhookEvent::InfringementEnded, WebhookEvent::AlertFired]
+        );
+        assert!(events("infringement.deleted").is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_hosts_fail_before_sending() {
+        assert!(config(&[]).unwrap().unwrap().transport().is_ok());
+        for tls in ["starttls", "tls", "none"] {
+            let config = config(&[
+                ("BIRDNEST_SMTP_HOST", "smtp example"),
+                ("BIRDNEST_SMTP_TLS", tls),
+            ]);
+            assert!(config.unwrap().unwrap().transport().is_err(), "{tls}");
+        }
+    }
+
+    #[test]
+    fn digests_list_every_email() {
+        let email = |subject: &str| Email {
+            subject: subject.to_string(),
+            body: format!("About {subject}\n"),
+        };
+        let one = digest(&[email("SN-1")]);
+        assert_eq!(one.subject, "1 drone notification");
+        assert_eq!(one.body, "== SN-1 ==\n\nAbout SN-1\n\n");
+        let two = digest(&[email("SN-1"), email("SN-2")]);
+        assert_eq!(two.subject, "2 drone notifications");
+        assert!(two.body.ends_with("== SN-2 ==\n\nAbout SN-2\n\n"));
+    }
+
+    #[test]
+    fn infringement_emails_redact_the_pilot() {
+        let at = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let infringement = Infringement {
+            pilot: Some(pilot("P-email-test")),
+            ..infringement("SN-email-test", at)
+        };
+        let email =
+            render_infringement(WebhookEvent::InfringementCreated, &infringement, None).unwrap();
+        assert_eq!(email.subject, "Drone SN-email-test entered ndz");
+        assert!(email.body.contains("Zone:      ndz"));
+        assert!(email.body.contains("P-email-test"));
+        assert!(!email.body.contains("test@example.com"));
+        assert!(!email.body.contains("+000"));
+
+        let email = render_infringement(
+            WebhookEvent::InfringementTierChanged,
+            &Infringement {
+                tier: Tier::Critical,
+                ..infringement
+            },
+            Some(Tier::Warning),
+        )
+        .unwrap();
+        assert_eq!(
+            email.subject,
+            "Drone SN-email-test reached critical in ndz, was warning"
+        );
+    }
+}
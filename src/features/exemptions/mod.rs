@@ -0,0 +1,187 @@
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{error, info};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    reaktor::{drones::Drone, pilots::Pilot},
+    storage::{load_json, save_json},
+};
+
+lazy_static! {
+    /// Loaded from [exemptions_file] by [init]
+    static ref EXEMPTIONS: RwLock<Vec<Exemption>> =
+        RwLock::new(load().unwrap_or_else(|e| panic!("{e:#}")));
+}
+
+/// Load the exemptions, so an unreadable exemption file stops the server at startup
+pub fn init() -> Result<()> {
+    load()?;
+    lazy_static::initialize(&EXEMPTIONS);
+    Ok(())
+}
+
+/// Read from BIRDNEST_EXEMPTIONS_FILE, defaults to `exemptions.json`
+fn exemptions_file() -> PathBuf {
+    PathBuf::from(
+        std::env::var("BIRDNEST_EXEMPTIONS_FILE").unwrap_or_else(|_| "exemptions.json".to_string()),
+    )
+}
+
+/// What an exemption is matched against
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExemptionKind {
+    SerialNumber,
+    Mac,
+    PilotId,
+}
+
+/// An exemption without the fields the server fills in
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct NewExemption {
+    pub kind: ExemptionKind,
+    /// The drone serial number, mac address or pilot id
+    #[openapi(example = "SN-fqdF8EI8jF")]
+    pub value: String,
+    /// Valid from the beginning of time if left out
+    pub valid_from: Option<DateTime<Utc>>,
+    /// Valid forever if left out
+    pub valid_until: Option<DateTime<Utc>>,
+    /// Zones the exemption applies to, every zone if empty
+    #[serde(default)]
+    pub zones: Vec<String>,
+    /// Who or what the exemption is for
+    #[openapi(example = "Survey drone of the bird society")]
+    pub note: Option<String>,
+}
+
+// The fields of NewExemption are written out, paperclip leaves flattened fields out of the
+// OpenAPI spec
+/// A drone or pilot that is allowed to fly in the zones
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct Exemption {
+    pub id: u64,
+    pub created_at: DateTime<Utc>,
+    pub kind: ExemptionKind,
+    /// The drone serial number, mac address or pilot id
+    #[openapi(example = "SN-fqdF8EI8jF")]
+    pub value: String,
+    /// Valid from the beginning of time if left out
+    pub valid_from: Option<DateTime<Utc>>,
+    /// Valid forever if left out
+    pub valid_until: Option<DateTime<Utc>>,
+    /// Zones the exemption applies to, every zone if empty
+    #[serde(default)]
+    pub zones: Vec<String>,
+    /// Who or what the exemption is for
+    #[openapi(example = "Survey drone of the bird society")]
+    pub note: Option<String>,
+}
+
+impl Exemption {
+    fn matches(
+        &self,
+        drone: &Drone,
+        pilot: Option<&Pilot>,
+        zone: &str,
+        time: DateTime<Utc>,
+    ) -> bool {
+        let subject = match self.kind {
+            ExemptionKind::SerialNumber => drone.serial_number == self.value,
+            ExemptionKind::Mac => drone.mac.eq_ignore_ascii_case(&self.value),
+            ExemptionKind::PilotId => pilot.is_some_and(|p| p.pilot_id == self.value),
+        };
+        subject
+            && self.valid_from.is_none_or(|from| time >= from)
+            && self.valid_until.is_none_or(|until| time < until)
+            && (self.zones.is_empty() || self.zones.iter().any(|z| z == zone))
+    }
+}
+
+fn load() -> Result<Vec<Exemption>> {
+    let path = exemptions_file();
+    let exemptions = load_json::<Vec<Exemption>>(&path)
+        .with_context(|| format!("Failed to load exemptions from {}", path.display()))?
+        .unwrap_or_default();
+    info!(
+        "Loaded {} exemptions from {}",
+        exemptions.len(),
+        path.display()
+    );
+    Ok(exemptions)
+}
+
+fn save(exemptions: &[Exemption]) -> Result<()> {
+    save_json(&exemptions_file(), exemptions)
+}
+
+pub fn list_exemptions() -> Vec<Exemption> {
+    EXEMPTIONS.read().unwrap().clone()
+}
+
+pub fn add_exemption(exemption: NewExemption) -> Result<Exemption> {
+    if exemption.value.is_empty() {
+        bail!("value can not be empty");
+    }
+    if let (Some(from), Some(until)) = (exemption.valid_from, exemption.valid_until) {
+        if until <= from {
+            bail!("valid_until has to be after valid_from");
+        }
+    }
+    let mut exemptions = EXEMPTIONS.write().unwrap();
+    let exemption = Exemption {
+        id: exemptions.iter().map(|e| e.id).max().unwrap_or_default() + 1,
+        created_at: Utc::now(),
+        kind: exemption.kind,
+        value: exemption.value,
+        valid_from: exemption.valid_from,
+        valid_until: exemption.valid_until,
+        zones: exemption.zones,
+        note: exemption.note,
+    };
+    exemptions.push(exemption.clone());
+    if let Err(e) = save(&exemptions) {
+        exemptions.pop();
+        return Err(e);
+    }
+    Ok(exemption)
+}
+
+pub fn delete_exemption(id: u64) -> Result<()> {
+    let mut exemptions = EXEMPTIONS.write().unwrap();
+    let Some(index) = exemptions.iter().position(|e| e.id == id) else {
+        bail!("No exemption with id {id}");
+    };
+    let removed = exemptions.remove(index);
+    if let Err(e) = save(&exemptions) {
+        exemptions.insert(index, removed);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// The first exemption allowing the drone in the zone at the given time
+pub fn find_exemption(
+    drone: &Drone,
+    pilot: Option<&Pilot>,
+    zone: &str,
+    time: DateTime<Utc>,
+) -> Option<u64> {
+    let exemptions = match EXEMPTIONS.read() {
+        Ok(exemptions) => exemptions,
+        Err(e) => {
+            error!("Exemption registry is poisoned: {e}");
+            return None;
+        }
+    };
+    exemptions
+        .iter()
+        .find(|e| e.matches(drone, pilot, zone, time))
+        .map(|e| e.id)
+}
@@ -0,0 +1,279 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Tier,
+    features::{history::read_history, review::ReviewState},
+    filter::InfringementFilter,
+    Infringement, InfringementStatus,
+};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+    Geojson,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Geojson => "application/geo+json",
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Geojson => "geojson",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportSource {
+    /// Infringements from the last 10 minutes
+    #[default]
+    Current,
+    /// Infringements that have already left the cache
+    History,
+}
+
+/// An [Infringement] with the pilot fields flattened, so every format has the same columns
+#[derive(Serialize, Debug, Clone)]
+pub struct ExportRecord {
+    pub drone_serial_number: String,
+    pub status: InfringementStatus,
+    pub exemption_id: Option<u64>,
+    pub review_state: ReviewState,
+    pub zone: String,
+    pub tier: Tier,
+    pub severity: f64,
+    pub distance: f64,
+    pub x: f64,
+    pub y: f64,
+    pub closest_approach_at: DateTime<Utc>,
+    pub closest_approach_altitude: f64,
+    pub first_seen_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub snapshots_inside: u32,
+    pub updated_at: DateTime<Utc>,
+    pub pilot_id: Option<String>,
+    pub pilot_first_name: Option<String>,
+    pub pilot_last_name: Option<String>,
+    pub pilot_phone_number: Option<String>,
+    pub pilot_email: Option<String>,
+    pub pilot_created_date: Option<String>,
+}
+
+impl From<Infringement> for ExportRecord {
+    fn from(i: Infringement) -> Self {
+        let pilot = i.pilot;
+        Self {
+            drone_serial_number: i.drone_serial_number,
+            status: i.status,
+            exemption_id: i.exemption_id,
+            review_state: i.review.map(|r| r.state).unwrap_or_default(),
+            zone: i.zone,
+            tier: i.tier,
+            severity: i.severity,
+            distance: i.distance,
+            x: i.x,
+            y: i.y,
+            closest_approach_at: i.closest_approach_at,
+            closest_approach_altitude: i.closest_approach_altitude,
+            first_seen_at: i.first_seen_at,
+            last_seen_at: i.last_seen_at,
+            snapshots_inside: i.snapshots_inside,
+            updated_at: i.updated_at,
+            pilot_id: pilot.as_ref().map(|p| p.pilot_id.clone()),
+            pilot_first_name: pilot.as_ref().map(|p| p.first_name.clone()),
+            pilot_last_name: pilot.as_ref().map(|p| p.last_name.clone()),
+            pilot_phone_number: pilot.as_ref().map(|p| p.phone_number.clone()),
+            pilot_email: pilot.as_ref().map(|p| p.email.clone()),
+            pilot_created_date: pilot.as_ref().map(|p| p.created_date.clone()),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "drone_serial_number,status,exemption_id,review_state,zone,tier,severity,distance,x,y,closest_approach_at,closest_approach_altitude,first_seen_at,last_seen_at,snapshots_inside,updated_at,pilot_id,pilot_first_name,pilot_last_name,pilot_phone_number,pilot_email,pilot_created_date\n";
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv_row(r: &ExportRecord) -> String {
+    let optional = |v: &Option<String>| csv_field(v.as_deref().unwrap_or_default());
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+        csv_field(&r.drone_serial_number),
+        r.status.as_str(),
+        r.exemption_id.map(|id| id.to_string()).unwrap_or_default(),
+        r.review_state.as_str(),
+        csv_field(&r.zone),
+        r.tier.as_str(),
+        r.severity,
+        r.distance,
+        r.x,
+        r.y,
+        r.closest_approach_at.to_rfc3339(),
+        r.closest_approach_altitude,
+        r.first_seen_at.to_rfc3339(),
+        r.last_seen_at.to_rfc3339(),
+        r.snapshots_inside,
+        r.updated_at.to_rfc3339(),
+        optional(&r.pilot_id),
+        optional(&r.pilot_first_name),
+        optional(&r.pilot_last_name),
+        optional(&r.pilot_phone_number),
+        optional(&r.pilot_email),
+        optional(&r.pilot_created_date),
+    )
+}
+
+fn geojson_feature(r: &ExportRecord) -> String {
+    serde_json::json!({
+        "type": "Feature",
+        "geometry": { "type": "Point", "coordinates": [r.x, r.y] },
+        "properties": r,
+    })
+    .to_string()
+}
+
+/// Everything written before the first record
+fn header(format: ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Csv => CSV_HEADER,
+        ExportFormat::Ndjson => "",
+        ExportFormat::Geojson => "{\"type\":\"FeatureCollection\",\"features\":[",
+    }
+}
+
+/// Everything written after the last record
+fn footer(format: ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Geojson => "]}\n",
+        _ => "",
+    }
+}
+
+fn render(format: ExportFormat, index: usize, record: &ExportRecord) -> String {
+    match format {
+        ExportFormat::Csv => csv_row(record),
+        ExportFormat::Ndjson => {
+            serde_json::to_string(record).expect("Records always serialize") + "\n"
+        }
+        ExportFormat::Geojson if index == 0 => geojson_feature(record),
+        ExportFormat::Geojson => format!(",{}", geojson_feature(record)),
+    }
+}
+
+/// Render the infringements one chunk at a time
+pub fn export_chunks(
+    format: ExportFormat,
+    infringements: impl IntoIterator<Item = Infringement>,
+) -> impl Iterator<Item = String> {
+    let records = infringements
+        .into_iter()
+        .map(ExportRecord::from)
+        .enumerate()
+        .map(move |(i, record)| render(format, i, &record));
+    std::iter::once(header(format).to_string())
+        .chain(records)
+        .chain(std::iter::once(footer(format).to_string()))
+}
+
+/// [export_chunks] as a stream, for streaming http responses. The chunks are rendered on a
+/// blocking thread, the infringements can be read from the history file
+pub fn export_stream<I>(format: ExportFormat, infringements: I) -> impl Stream<Item = String>
+where
+    I: IntoIterator<Item = Infringement>,
+    I::IntoIter: Send + 'static,
+{
+    let infringements = infringements.into_iter();
+    let (sender, receiver) = tokio::sync::mpsc::channel(64);
+    tokio::task::spawn_blocking(move || {
+        for chunk in export_chunks(format, infringements) {
+            // The client has gone away
+            if sender.blocking_send(chunk).is_err() {
+                break;
+            }
+        }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+}
+
+/// Handles `birdnest-api export [--format csv|ndjson|geojson] [--min-updated-at <time>]
+/// [--max-updated-at <time>] [--min-tier <tier>] [--zone <id>] [--sort <order>] [--output <file>]`,
+/// exporting the history
+pub fn run_cli(args: &[String]) -> Result<()> {
+    let mut format = ExportFormat::default();
+    let mut filter = InfringementFilter::default();
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().with_context(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--format" => {
+                format = serde_json::from_value(serde_json::Value::String(value()?.clone()))
+                    .context("Expected csv, ndjson or geojson")?
+            }
+            "--min-updated-at" => filter.min_updated_at = Some(value()?.clone()),
+            "--max-updated-at" => filter.max_updated_at = Some(value()?.clone()),
+            "--min-tier" => {
+                filter.min_tier = Some(
+                    serde_json::from_value(serde_json::Value::String(value()?.clone()))
+                        .context("Expected advisory, warning or critical")?,
+                )
+            }
+            "--zone" => filter.zone = Some(value()?.clone()),
+            "--sort" => {
+                filter.sort = Some(
+                    serde_json::from_value(serde_json::Value::String(value()?.clone()))
+                        .context("Expected severity, distance or updated_at")?,
+                )
+            }
+            "--output" => output = Some(value()?.clone()),
+            other => bail!("Unknown argument \"{other}\""),
+        }
+    }
+    let infringements = filter_history(&filter)?;
+
+    let mut out: Box<dyn std::io::Write> = match &output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    for chunk in export_chunks(format, infringements) {
+        out.write_all(chunk.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// The archived infringements matching the filter. They are streamed from the file,
+/// unless they have to be sorted
+pub fn filter_history(
+    filter: &InfringementFilter,
+) -> Result<Box<dyn Iterator<Item = Infringement> + Send>> {
+    let matches = filter.matcher()?;
+    let history = read_history()?.filter(move |i| matches(i));
+    if filter.sort.is_none() {
+        return Ok(Box::new(history));
+    }
+    let mut infringements: Vec<_> = history.collect();
+    filter.sort(&mut infringements);
+    Ok(Box::new(infringements.into_iter()))
+}
@@ -0,0 +1,62 @@
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use anyhow::Result;
+use log::{debug, error, warn};
+
+use crate::Infringement;
+
+const HISTORY_FILE: &str = "infringements.ndjson";
+
+/// Read from BIRDNEST_HISTORY_DIR, defaults to `history`
+fn history_dir() -> PathBuf {
+    PathBuf::from(std::env::var("BIRDNEST_HISTORY_DIR").unwrap_or_else(|_| "history".to_string()))
+}
+
+/// Append an infringement that has left the cache to the history,
+/// so it can still be exported afterwards
+pub fn archive(infringement: &Infringement) {
+    debug!(
+        "Archiving infringement of drone {}",
+        infringement.drone_serial_number
+    );
+    if let Err(e) = append(infringement) {
+        error!("Failed to archive infringement: {e}");
+    }
+}
+
+fn append(infringement: &Infringement) -> Result<()> {
+    let dir = history_dir();
+    std::fs::create_dir_all(&dir)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(HISTORY_FILE))?;
+    writeln!(file, "{}", serde_json::to_string(infringement)?)?;
+    Ok(())
+}
+
+/// Every archived infringement, oldest first.
+/// The file is read one line at a time, so the history never has to fit in memory
+pub fn read_history() -> Result<impl Iterator<Item = Infringement>> {
+    let path = history_dir().join(HISTORY_FILE);
+    let lines = match std::fs::File::open(path) {
+        Ok(file) => Some(std::io::BufReader::new(file).lines()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    Ok(lines
+        .into_iter()
+        .flatten()
+        // Stop at the first read error, retrying it would fail the same way
+        .map_while(|line| {
+            line.map_err(|e| error!("Failed to read the history: {e}"))
+                .ok()
+        })
+        .filter_map(|line| {
+            serde_json::from_str(&line)
+                // A crash while appending can leave a partial line behind
+                .map_err(|e| warn!("Skipping unreadable history entry: {e}"))
+                .ok()
+        }))
+}
@@ -1 +1,12 @@
+pub mod airspace;
+pub mod alerts;
+pub mod email;
+pub mod exemptions;
+pub mod export;
+pub mod history;
+pub mod pilots;
+pub mod privacy;
 pub mod replay;
+pub mod review;
+pub mod templates;
+pub mod webhooks;
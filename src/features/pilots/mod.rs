@@ -0,0 +1,115 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use chrono::{DateTime, Utc};
+use paperclip::actix::Apiv2Schema;
+use serde::Serialize;
+
+use crate::{
+    config::Tier, features::review::ReviewState, reaktor::pilots::Pilot, Infringement,
+    InfringementStatus,
+};
+
+/// Everything a pilot has done, over every drone they have flown
+#[derive(Serialize, Debug, Clone, Apiv2Schema)]
+pub struct PilotSummary {
+    pub pilot: Pilot,
+    /// How many infringements the pilot's drones have caused
+    pub infringement_count: usize,
+    /// The closest any of the pilot's drones has come to a zone center
+    pub closest_distance: f64,
+    pub worst_tier: Tier,
+    pub first_offense_at: DateTime<Utc>,
+    pub last_offense_at: DateTime<Utc>,
+    /// Serial numbers of the drones the pilot has used
+    pub drones: BTreeSet<String>,
+}
+
+impl PilotSummary {
+    fn new(pilot: Pilot, i: &Infringement) -> Self {
+        Self {
+            pilot,
+            infringement_count: 0,
+            closest_distance: i.distance,
+            worst_tier: i.tier,
+            first_offense_at: i.first_seen_at,
+            last_offense_at: i.last_seen_at,
+            drones: BTreeSet::new(),
+        }
+    }
+
+    fn add(&mut self, i: &Infringement) {
+        self.infringement_count += 1;
+        self.closest_distance = self.closest_distance.min(i.distance);
+        self.worst_tier = self.worst_tier.max(i.tier);
+        self.first_offense_at = self.first_offense_at.min(i.first_seen_at);
+        self.last_offense_at = self.last_offense_at.max(i.last_seen_at);
+        self.drones.insert(i.drone_serial_number.clone());
+    }
+}
+
+/// Whether an infringement counts against the pilot,
+/// authorized flights and dismissed false positives do not
+fn is_offense(i: &Infringement) -> bool {
+    i.status == InfringementStatus::Infringing
+        && i.review.as_ref().map(|r| r.state) != Some(ReviewState::Dismissed)
+}
+
+/// Current and archived infringements can overlap, reviewed infringements are both
+/// archived and kept with their review. The current version wins.
+/// The history is only read as far as the returned iterator is
+pub fn deduplicate(
+    current: Vec<Infringement>,
+    history: impl IntoIterator<Item = Infringement>,
+) -> impl Iterator<Item = Infringement> {
+    let seen: HashSet<(String, DateTime<Utc>)> = current
+        .iter()
+        .map(|i| (i.drone_serial_number.clone(), i.first_seen_at))
+        .collect();
+    current.into_iter().chain(
+        history
+            .into_iter()
+            .filter(move |i| !seen.contains(&(i.drone_serial_number.clone(), i.first_seen_at))),
+    )
+}
+
+/// Group the offenses by pilot, repeat offenders first
+pub fn summarize<I>(infringements: impl IntoIterator<Item = I>) -> Vec<PilotSummary>
+where
+    I: Borrow<Infringement>,
+{
+    let mut pilots: BTreeMap<String, PilotSummary> = BTreeMap::new();
+    for i in infringements.into_iter() {
+        let i = i.borrow();
+        if !is_offense(i) {
+            continue;
+        }
+        let Some(pilot) = &i.pilot else {
+            continue;
+        };
+        pilots
+            .entry(pilot.pilot_id.clone())
+            .or_insert_with(|| PilotSummary::new(pilot.clone(), i))
+            .add(i);
+    }
+    let mut pilots: Vec<_> = pilots.into_values().collect();
+    pilots.sort_by(|a, b| {
+        b.infringement_count
+            .cmp(&a.infringement_count)
+            .then(b.last_offense_at.cmp(&a.last_offense_at))
+    });
+    pilots
+}
+
+/// Offenses of a single pilot, newest first
+pub fn offenses_of(
+    pilot_id: &str,
+    infringements: impl IntoIterator<Item = Infringement>,
+) -> Vec<Infringement> {
+    let mut offenses: Vec<_> = infringements
+        .into_iter()
+        .filter(|i| is_offense(i) && i.pilot.as_ref().is_some_and(|p| p.pilot_id == pilot_id))
+        .collect();
+    offenses.sort_by_key(|i| std::cmp::Reverse(i.last_seen_at));
+    offenses
+}
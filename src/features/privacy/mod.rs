@@ -0,0 +1,162 @@
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::{reaktor::pilots::Pilot, Infringement};
+
+/// What public responses show of a pilot field
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+    /// The full value
+    Show,
+    /// Enough to tell values apart, like `j***@example.com` or `+358*****67`
+    Partial,
+    /// Replaced with [REDACTED]
+    Hide,
+}
+
+pub const REDACTED: &str = "[REDACTED]";
+
+/// How each pilot field is redacted for callers without the read:pii scope
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedactionPolicy {
+    pub pilot_id: Redaction,
+    pub first_name: Redaction,
+    pub last_name: Redaction,
+    pub phone_number: Redaction,
+    pub email: Redaction,
+    pub created_date: Redaction,
+}
+
+impl Default for RedactionPolicy {
+    fn default() -> Self {
+        Self {
+            // Needed to look pilots up with /pilots/{pilot_id}
+            pilot_id: Redaction::Show,
+            first_name: Redaction::Show,
+            last_name: Redaction::Partial,
+            phone_number: Redaction::Hide,
+            email: Redaction::Partial,
+            created_date: Redaction::Show,
+        }
+    }
+}
+
+impl RedactionPolicy {
+    /// Parses comma separated `field=show|partial|hide` pairs,
+    /// fields that are not mentioned keep their default
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut policy = Self::default();
+        for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (field, redaction) = pair
+                .split_once('=')
+                .with_context(|| format!("Expected field=redaction, got \"{pair}\""))?;
+            let redaction = serde_json::from_value(serde_json::Value::String(redaction.into()))
+                .with_context(|| {
+                    format!("Unknown redaction \"{redaction}\", expected show, partial or hide")
+                })?;
+            let field = match field {
+                "pilot_id" => &mut policy.pilot_id,
+                "first_name" => &mut policy.first_name,
+                "last_name" => &mut policy.last_name,
+                "phone_number" => &mut policy.phone_number,
+                "email" => &mut policy.email,
+                "created_date" => &mut policy.created_date,
+                other => bail!("Unknown pilot field \"{other}\""),
+            };
+            *field = redaction;
+        }
+        Ok(policy)
+    }
+
+    pub fn redact_pilot(&self, pilot: &mut Pilot) {
+        apply(self.pilot_id, &mut pilot.pilot_id, mask_tail);
+        apply(self.first_name, &mut pilot.first_name, initial);
+        apply(self.last_name, &mut pilot.last_name, initial);
+        apply(self.phone_number, &mut pilot.phone_number, mask_phone);
+        apply(self.email, &mut pilot.email, mask_email);
+        apply(self.created_date, &mut pilot.created_date, date_only);
+    }
+}
+
+lazy_static! {
+    /// Read from BIRDNEST_REDACT, for example `email=hide,phone_number=partial`
+    pub static ref REDACTION_POLICY: RedactionPolicy =
+        load_policy().unwrap_or_else(|e| panic!("{e:#}"));
+}
+
+fn load_policy() -> Result<RedactionPolicy> {
+    match std::env::var("BIRDNEST_REDACT") {
+        Ok(value) => RedactionPolicy::parse(&value).context("Invalid BIRDNEST_REDACT"),
+        Err(_) => Ok(RedactionPolicy::default()),
+    }
+}
+
+/// Parse BIRDNEST_REDACT, so an invalid policy stops the server at startup
+pub fn init() -> Result<()> {
+    load_policy()?;
+    lazy_static::initialize(&REDACTION_POLICY);
+    Ok(())
+}
+
+fn apply(redaction: Redaction, value: &mut String, partial: fn(&str) -> String) {
+    match redaction {
+        Redaction::Show => {}
+        Redaction::Partial => *value = partial(value),
+        Redaction::Hide => *value = REDACTED.to_string(),
+    }
+}
+
+fn initial(value: &str) -> String {
+    value
+        .chars()
+        .next()
+        .map(|c| format!("{c}."))
+        .unwrap_or_default()
+}
+
+fn mask_tail(value: &str) -> String {
+    let shown: String = value.chars().take(3).collect();
+    format!("{shown}***")
+}
+
+/// Keeps the country code and the last two digits
+fn mask_phone(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    chars
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            if i < 4 || i + 2 >= chars.len() || !c.is_ascii_digit() {
+                *c
+            } else {
+                '*'
+            }
+        })
+        .collect()
+}
+
+fn mask_email(value: &str) -> String {
+    match value.split_once('@') {
+        Some((local, domain)) => format!("{}***@{domain}", local.chars().next().unwrap_or('*')),
+        None => mask_tail(value),
+    }
+}
+
+fn date_only(value: &str) -> String {
+    value.chars().take(10).collect()
+}
+
+/// Redact the pilot of every infringement with the configured policy
+pub fn redact_infringements(infringements: &mut [Infringement]) {
+    for infringement in infringements {
+        redact_infringement(infringement);
+    }
+}
+
+pub fn redact_infringement(infringement: &mut Infringement) {
+    if let Some(pilot) = infringement.pilot.as_mut() {
+        REDACTION_POLICY.redact_pilot(pilot);
+    }
+}
@@ -0,0 +1,468 @@
+//! Compact storage for recorded drone snapshots.
+//!
+//! Consecutive snapshots mostly contain the same drones, so the attributes that identify a drone
+//! are written once per serial number and every snapshot only stores how the position and
+//! altitude of each drone changed. Positions are rounded to [POSITION_SCALE]ths of a sensor unit
+//! and every change is stored as a zigzag encoded varint of the difference, so the few meters a
+//! drone moves between snapshots take three or four bytes instead of eight.
+//!
+//! A recording is a sequence of records, each starting with a tag byte:
+//! - [TAG_IDENTITY]: the identity of a drone, replacing any earlier identity with the same id
+//! - [TAG_SNAPSHOT]: sensor information followed by the drones seen in the snapshot
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::reaktor::drones::{Drone, DronesCapture, DronesDocument, DronesSensorInfo};
+
+const TAG_IDENTITY: u8 = 0;
+const TAG_SNAPSHOT: u8 = 1;
+/// Positions are stored in thousandths of a sensor unit, micrometers, far below what the
+/// sensor measures
+const POSITION_SCALE: f64 = 1000.0;
+
+/// Drone attributes that do not change from one snapshot to the next
+#[derive(Debug, Clone, PartialEq)]
+struct DroneIdentity {
+    serial_number: String,
+    model: String,
+    manufacturer: String,
+    mac: String,
+    ipv4: String,
+    ipv6: String,
+    firmware: String,
+}
+
+impl From<&Drone> for DroneIdentity {
+    fn from(drone: &Drone) -> Self {
+        Self {
+            serial_number: drone.serial_number.clone(),
+            model: drone.model.clone(),
+            manufacturer: drone.manufacturer.clone(),
+            mac: drone.mac.clone(),
+            ipv4: drone.ipv4.clone(),
+            ipv6: drone.ipv6.clone(),
+            firmware: drone.firmware.clone(),
+        }
+    }
+}
+
+/// Position and altitude in fixed point, in that order: x, y, altitude
+type Position = [i64; 3];
+
+fn position_of(drone: &Drone) -> Position {
+    [drone.position_x, drone.position_y, drone.altitude]
+        .map(|v| (v * POSITION_SCALE).round() as i64)
+}
+
+fn from_fixed(value: i64) -> f64 {
+    value as f64 / POSITION_SCALE
+}
+
+/// Small differences in either direction become small numbers
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+/// Turns snapshots into bytes, remembering what earlier snapshots already stored
+#[derive(Debug, Default, Clone)]
+pub struct Encoder {
+    ids: HashMap<String, u64>,
+    identities: Vec<DroneIdentity>,
+    positions: Vec<Position>,
+}
+
+impl Encoder {
+    /// Encode the next snapshot, the returned bytes are meant to be appended to earlier output.
+    /// Also returns the encoder for the snapshot after it, to use once the bytes are written
+    pub fn encode(&self, doc: &DronesDocument) -> (Vec<u8>, Encoder) {
+        let mut next = self.clone();
+        let out = next.encode_into(doc);
+        (out, next)
+    }
+
+    fn encode_into(&mut self, doc: &DronesDocument) -> Vec<u8> {
+        let mut out = vec![];
+        let mut drone_ids = Vec::with_capacity(doc.capture.drone.len());
+        for drone in &doc.capture.drone {
+            let identity = DroneIdentity::from(drone);
+            let id = match self.ids.get(&drone.serial_number) {
+                Some(&id) => id,
+                None => {
+                    let id = self.identities.len() as u64;
+                    self.ids.insert(drone.serial_number.clone(), id);
+                    self.identities.push(identity.clone());
+                    self.positions.push([0; 3]);
+                    write_identity(&mut out, id, &identity);
+                    id
+                }
+            };
+            // A serial number that shows up with new attributes gets its identity rewritten
+            if self.identities[id as usize] != identity {
+                write_identity(&mut out, id, &identity);
+                self.identities[id as usize] = identity;
+            }
+            drone_ids.push(id);
+        }
+
+        out.push(TAG_SNAPSHOT);
+        write_sensor_info(&mut out, &doc.device_information);
+        write_str(&mut out, &doc.capture.snapshot_timestamp);
+        write_varint(&mut out, drone_ids.len() as u64);
+        for (drone, id) in doc.capture.drone.iter().zip(drone_ids) {
+            write_varint(&mut out, id);
+            let previous = &mut self.positions[id as usize];
+            for (old, new) in previous.iter_mut().zip(position_of(drone)) {
+                write_varint(&mut out, zigzag(new.wrapping_sub(*old)));
+                *old = new;
+            }
+        }
+        out
+    }
+}
+
+/// Decode every snapshot of a recording
+pub fn decode(bytes: &[u8]) -> Result<Vec<DronesDocument>> {
+    let mut reader = Reader { bytes, offset: 0 };
+    let mut identities: Vec<DroneIdentity> = vec![];
+    let mut positions: Vec<Position> = vec![];
+    let mut documents = vec![];
+    while !reader.is_empty() {
+        match reader.byte()? {
+            TAG_IDENTITY => {
+                let id = reader.varint()? as usize;
+                let identity = read_identity(&mut reader)?;
+                if id == identities.len() {
+                    identities.push(identity);
+                    positions.push([0; 3]);
+                } else {
+                    *identities
+                        .get_mut(id)
+                        .ok_or_else(|| anyhow!("Identity {id} is out of order"))? = identity;
+                }
+            }
+            TAG_SNAPSHOT => {
+                let device_information = read_sensor_info(&mut reader)?;
+                let snapshot_timestamp = reader.string()?;
+                let count = reader.varint()? as usize;
+                // A corrupt count must not allocate more than the recording could hold
+                let mut drone = Vec::with_capacity(count.min(reader.remaining()));
+                for _ in 0..count {
+                    let id = reader.varint()? as usize;
+                    let identity = identities
+                        .get(id)
+                        .ok_or_else(|| anyhow!("Unknown drone identity {id}"))?
+                        .clone();
+                    let position = &mut positions[id];
+                    for value in position.iter_mut() {
+                        *value = value.wrapping_add(unzigzag(reader.varint()?));
+                    }
+                    drone.push(Drone {
+                        serial_number: identity.serial_number,
+                        model: identity.model,
+                        manufacturer: identity.manufacturer,
+                        mac: identity.mac,
+                        ipv4: identity.ipv4,
+                        ipv6: identity.ipv6,
+                        firmware: identity.firmware,
+                        position_x: from_fixed(position[0]),
+                        position_y: from_fixed(position[1]),
+                        altitude: from_fixed(position[2]),
+                    });
+                }
+                documents.push(DronesDocument {
+                    device_information,
+                    capture: DronesCapture {
+                        snapshot_timestamp,
+                        drone,
+                    },
+                    replay_run: None,
+                });
+            }
+            tag => bail!("Unknown record tag {tag} at byte {}", reader.offset - 1),
+        }
+    }
+    Ok(documents)
+}
+
+fn write_identity(out: &mut Vec<u8>, id: u64, identity: &DroneIdentity) {
+    out.push(TAG_IDENTITY);
+    write_varint(out, id);
+    for field in [
+        &identity.serial_number,
+        &identity.model,
+        &identity.manufacturer,
+        &identity.mac,
+        &identity.ipv4,
+        &identity.ipv6,
+        &identity.firmware,
+    ] {
+        write_str(out, field);
+    }
+}
+
+fn read_identity(reader: &mut Reader) -> Result<DroneIdentity> {
+    Ok(DroneIdentity {
+        serial_number: reader.string()?,
+        model: reader.string()?,
+        manufacturer: reader.string()?,
+        mac: reader.string()?,
+        ipv4: reader.string()?,
+        ipv6: reader.string()?,
+        firmware: reader.string()?,
+    })
+}
+
+fn write_sensor_info(out: &mut Vec<u8>, info: &DronesSensorInfo) {
+    write_optional(out, info.device_id.as_ref(), |out, v| write_str(out, v));
+    write_optional(out, info.listen_range, |out, v| write_varint(out, v as u64));
+    write_optional(out, info.device_started.as_ref(), |out, v| {
+        write_str(out, v)
+    });
+    write_optional(out, info.uptime_seconds, |out, v| {
+        write_varint(out, v as u64)
+    });
+    write_optional(out, info.update_interval_ms, |out, v| {
+        write_varint(out, v as u64)
+    });
+}
+
+fn read_sensor_info(reader: &mut Reader) -> Result<DronesSensorInfo> {
+    Ok(DronesSensorInfo {
+        device_id: reader.optional(Reader::string)?,
+        listen_range: reader.optional(|r| Ok(r.varint()? as usize))?,
+        device_started: reader.optional(Reader::string)?,
+        uptime_seconds: reader.optional(|r| Ok(r.varint()? as usize))?,
+        update_interval_ms: reader.optional(|r| Ok(r.varint()? as usize))?,
+    })
+}
+
+fn write_optional<T>(out: &mut Vec<u8>, value: Option<T>, write: impl Fn(&mut Vec<u8>, T)) {
+    match value {
+        Some(value) => {
+            out.push(1);
+            write(out, value);
+        }
+        None => out.push(0),
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    write_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// LEB128, small numbers take fewer bytes
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn is_empty(&self) -> bool {
+        self.offset >= self.bytes.len()
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len().saturating_sub(self.offset)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.offset)
+            .context("Recording ended unexpectedly")?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Varint at byte {} is too long", self.offset)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.varint()? as usize;
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .context("Recording ended unexpectedly")?;
+        let value = std::str::from_utf8(&self.bytes[self.offset..end])?.to_string();
+        self.offset = end;
+        Ok(value)
+    }
+
+    fn optional<T>(&mut self, read: impl Fn(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.byte()? {
+            0 => Ok(None),
+            _ => read(self).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drone(serial: &str, x: f64, y: f64, altitude: f64) -> Drone {
+        Drone {
+            serial_number: serial.to_string(),
+            model: "HRP-DRP 1 S".to_string(),
+            manufacturer: "ProDröne Ltd".to_string(),
+            mac: "c0:8d:c0:62:df:38".to_string(),
+            ipv4: "219.33.125.73".to_string(),
+            ipv6: "6eee:c1a4:0ca0:9ab8:0b1f:acb4:44a3:a283".to_string(),
+            firmware: "5.2.4".to_string(),
+            position_y: y,
+            position_x: x,
+            altitude,
+        }
+    }
+
+    fn snapshot(timestamp: &str, drones: Vec<Drone>) -> DronesDocument {
+        DronesDocument {
+            device_information: DronesSensorInfo {
+                device_id: Some("GUARDB1RD".to_string()),
+                listen_range: Some(500_000),
+                device_started: Some("2023-06-28T18:49:05.491Z".to_string()),
+                uptime_seconds: Some(10_000),
+                update_interval_ms: None,
+            },
+            capture: DronesCapture {
+                snapshot_timestamp: timestamp.to_string(),
+                drone: drones,
+            },
+            replay_run: None,
+        }
+    }
+
+    fn recording() -> Vec<DronesDocument> {
+        let mut renamed = drone("SN-a", 100_000.5, 200_000.25, 4_000.0);
+        renamed.firmware = "5.2.5".to_string();
+        vec![
+            snapshot(
+                "2023-06-28T21:51:21.000Z",
+                vec![
+                    drone("SN-a", 100_000.0, 200_000.0, 4_000.0),
+                    drone("SN-b", -1.5, 9_000_000_000.0, 0.0),
+                ],
+            ),
+            // SN-b left, SN-c arrived
+            snapshot(
+                "2023-06-28T21:51:23.000Z",
+                vec![
+                    drone("SN-c", 0.0, 0.0, 0.0),
+                    drone("SN-a", 100_000.5, 200_000.25, 4_000.0),
+                ],
+            ),
+            snapshot("2023-06-28T21:51:25.000Z", vec![]),
+            // The same serial number with new attributes
+            snapshot(
+                "2023-06-28T21:51:27.000Z",
+                vec![renamed, drone("SN-b", -1.5, 9_000_000_000.0, 0.0)],
+            ),
+        ]
+    }
+
+    fn encode_all(docs: &[DronesDocument]) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        docs.iter()
+            .flat_map(|doc| {
+                let (bytes, next) = encoder.encode(doc);
+                encoder = next;
+                bytes
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let docs = recording();
+        assert_eq!(decode(&encode_all(&docs)).unwrap(), docs);
+    }
+
+    #[test]
+    fn empty_recording() {
+        assert!(decode(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_recording_is_an_error() {
+        let bytes = encode_all(&recording());
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        // Every prefix either decodes into fewer snapshots or fails, never panics
+        for end in 0..bytes.len() {
+            if let Ok(docs) = decode(&bytes[..end]) {
+                assert!(docs.len() < recording().len());
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_recording_is_an_error() {
+        let mut bytes = encode_all(&recording());
+        bytes[0] = 7;
+        assert!(decode(&bytes).is_err());
+        // A snapshot claiming more drones than there are bytes
+        let mut huge = vec![TAG_SNAPSHOT];
+        write_sensor_info(&mut huge, &recording()[0].device_information);
+        write_str(&mut huge, "2023-06-28T21:51:21.000Z");
+        write_varint(&mut huge, u64::MAX >> 1);
+        assert!(decode(&huge).is_err());
+    }
+
+    #[test]
+    fn positions_are_rounded_to_micrometers() {
+        let docs = vec![snapshot(
+            "2023-06-28T21:51:21.000Z",
+            vec![drone("SN-a", 239168.47665144302, -0.0004, 4156.8799)],
+        )];
+        let decoded = decode(&encode_all(&docs)).unwrap();
+        let drone = &decoded[0].capture.drone[0];
+        assert_eq!(drone.position_x, 239168.477);
+        assert_eq!(drone.position_y, 0.0);
+        assert_eq!(drone.altitude, 4156.88);
+    }
+
+    #[test]
+    fn movements_take_few_bytes() {
+        let docs = crate::features::replay::load_drones(std::path::Path::new("replay")).unwrap();
+        let values: usize = docs.iter().map(|d| d.capture.drone.len() * 3).sum();
+        assert!(values > 1000);
+        // Without movement every value takes a single byte, the rest is the same
+        let mut still = docs.clone();
+        for drone in still.iter_mut().flat_map(|d| d.capture.drone.iter_mut()) {
+            drone.position_x = 0.0;
+            drone.position_y = 0.0;
+            drone.altitude = 0.0;
+        }
+        let position_bytes = encode_all(&docs).len() - encode_all(&still).len() + values;
+        let per_value = position_bytes as f64 / values as f64;
+        assert!(per_value < 4.0, "{per_value} bytes per value");
+    }
+}
@@ -1,16 +1,154 @@
+pub mod codec;
+pub mod recording;
+pub mod session;
+
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
 
-use log::debug;
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use log::{debug, error, info};
 use paperclip::actix::Apiv2Schema;
 use serde::Serialize;
+use tokio::sync::Notify;
 
 use crate::{
     cache::{LATEST_DRONE_SNAPSHOT, PILOT_CACHE},
     reaktor::{drones::DronesDocument, pilots::Pilot},
 };
+use recording::{is_recording, record_drones, recording_session_id};
+use session::{replay_dir, session_path, DELTA_FILE};
 
 const SAVE_DIR: &str = "replay";
 
+/// How far the replay has got
+static REPLAY_POSITION: Mutex<ReplayPosition> = Mutex::new(ReplayPosition {
+    position: 0,
+    run: 0,
+});
+
+struct ReplayPosition {
+    /// How many snapshots have been replayed since the replay started
+    position: usize,
+    /// Increases every time the replay starts over from the first snapshot
+    run: u64,
+}
+
+lazy_static! {
+    /// Notified once a replay in [ReplayEndMode::Shutdown] mode has played every snapshot
+    static ref REPLAY_FINISHED: Notify = Notify::new();
+    static ref REPLAY_END_MODE: ReplayEndMode =
+        read_replay_end_mode().unwrap_or_else(|e| panic!("{e}"));
+}
+
+/// What happens after the last snapshot of a replay has been played
+#[derive(Serialize, Debug, Apiv2Schema, PartialEq, Clone, Copy)]
+pub enum ReplayEndMode {
+    /// Start again from the first snapshot
+    Loop,
+    /// Start again from the first snapshot, forgetting the infringements of the previous loop
+    LoopAndClear,
+    /// Keep replaying the last snapshot
+    Hold,
+    /// Keep replaying the last snapshot and shut the server down
+    Shutdown,
+}
+
+/// Read from --replay-end or BIRDNEST_REPLAY_END, defaults to [ReplayEndMode::LoopAndClear]
+fn read_replay_end_mode() -> Result<ReplayEndMode> {
+    let args: Vec<String> = std::env::args().collect();
+    let mode = args
+        .iter()
+        .position(|a| a == "--replay-end")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("BIRDNEST_REPLAY_END").ok());
+    Ok(match mode.as_deref() {
+        None | Some("loop-clear") => ReplayEndMode::LoopAndClear,
+        Some("loop") => ReplayEndMode::Loop,
+        Some("hold") => ReplayEndMode::Hold,
+        Some("shutdown") => ReplayEndMode::Shutdown,
+        Some(other) => bail!(
+            "Unknown replay end mode \"{other}\", expected loop, loop-clear, hold or shutdown"
+        ),
+    })
+}
+
+/// Check the replay options once at startup
+pub fn init() -> Result<()> {
+    read_replay_end_mode()?;
+    lazy_static::initialize(&REPLAY_END_MODE);
+    Ok(())
+}
+
+pub fn get_replay_end_mode() -> ReplayEndMode {
+    *REPLAY_END_MODE
+}
+
+pub(crate) struct ReplayStep {
+    /// Index of the snapshot to replay
+    pub index: usize,
+    /// Whether infringements from the previous loop should be forgotten first
+    pub clear: bool,
+    /// The run of the replay the snapshot belongs to, see [DronesDocument::replay_run]
+    pub run: u64,
+}
+
+/// Picks the next snapshot to replay out of `history_len` snapshots
+pub(crate) fn next_replay_step(history_len: usize) -> ReplayStep {
+    let mut replay = REPLAY_POSITION.lock().unwrap();
+    let position = replay.position;
+    replay.position += 1;
+    if position < history_len {
+        return ReplayStep {
+            index: position,
+            clear: false,
+            run: replay.run,
+        };
+    }
+    match get_replay_end_mode() {
+        mode @ (ReplayEndMode::Loop | ReplayEndMode::LoopAndClear) => {
+            let index = position % history_len;
+            if index == 0 {
+                replay.run += 1;
+            }
+            ReplayStep {
+                index,
+                clear: index == 0 && mode == ReplayEndMode::LoopAndClear,
+                run: replay.run,
+            }
+        }
+        ReplayEndMode::Hold => ReplayStep {
+            index: history_len - 1,
+            clear: false,
+            run: replay.run,
+        },
+        ReplayEndMode::Shutdown => {
+            if position == history_len {
+                info!("Every snapshot has been replayed, shutting down");
+                REPLAY_FINISHED.notify_one();
+            }
+            ReplayStep {
+                index: history_len - 1,
+                clear: false,
+                run: replay.run,
+            }
+        }
+    }
+}
+
+/// Resolves once a replay in [ReplayEndMode::Shutdown] mode has finished
+pub async fn replay_finished() {
+    REPLAY_FINISHED.notified().await
+}
+
+/// Start the next replay from the first snapshot
+pub(crate) fn reset_replay_position() {
+    let mut replay = REPLAY_POSITION.lock().unwrap();
+    replay.position = 0;
+    replay.run += 1;
+}
+
 pub fn ensure_dir_exists() {
     let path = std::path::Path::new(SAVE_DIR);
     if !path.exists() {
@@ -25,10 +163,15 @@ pub enum ReplayStatus {
     Replaying,
 }
 
+/// Replaying is chosen when the process starts with --replay or BIRDNEST_REPLAY
+pub fn is_replaying() -> bool {
+    std::env::args().any(|a| a == "--replay") || std::env::var("BIRDNEST_REPLAY").is_ok()
+}
+
 pub fn get_replay_status() -> ReplayStatus {
-    let replay =
-        std::env::args().any(|a| a == "--replay") || std::env::var("BIRDNEST_REPLAY").is_ok();
-    let record = std::env::args().any(|a| a == "--record");
+    let replay = is_replaying();
+    // Recordings can also be started and stopped at runtime
+    let record = is_recording();
     if replay && record {
         panic!("Cannot replay and record at the same time, remove either --replay, BIRDNEST_REPLAY or --record");
     }
@@ -49,8 +192,12 @@ pub async fn save(time: chrono::DateTime<chrono::Utc>) {
 }
 
 pub async fn save_replay_pilots() {
+    // Pilots are stored next to the drones of the session that is being recorded
+    let Some(dir) = recording_session_id().and_then(|id| session_path(&id).ok()) else {
+        return;
+    };
     debug!("Saving pilots");
-    let pilots_existing = load_replay_pilots();
+    let pilots_existing = load_pilots(&dir);
     debug!("{} pilots in fs", pilots_existing.len());
     let mut pilots = pilots_existing.clone();
     let cache = PILOT_CACHE.lock().await.clone();
@@ -61,10 +208,8 @@ pub async fn save_replay_pilots() {
     std::mem::drop(cache);
     if pilots == pilots_existing {
         debug!("No changes to pilots, skipping");
-        return;
     } else {
-        ensure_dir_exists();
-        let path = std::path::Path::new(SAVE_DIR).join("pilots.json");
+        let path = dir.join("pilots.json");
         debug!("Saving pilot document to {}", path.display());
         std::fs::write(path, serde_json::to_string(&pilots).unwrap()).unwrap();
     }
@@ -72,7 +217,11 @@ pub async fn save_replay_pilots() {
 
 pub(crate) fn load_replay_pilots() -> HashMap<String, Pilot> {
     ensure_dir_exists();
-    let path = std::path::Path::new(SAVE_DIR).join("pilots.json");
+    load_pilots(&replay_dir())
+}
+
+fn load_pilots(dir: &Path) -> HashMap<String, Pilot> {
+    let path = dir.join("pilots.json");
     if path.exists() {
         let content = std::fs::read_to_string(path).unwrap();
         serde_json::from_str(&content).unwrap()
@@ -82,43 +231,51 @@ pub(crate) fn load_replay_pilots() -> HashMap<String, Pilot> {
 }
 
 async fn save_replay_drones(time: chrono::DateTime<chrono::Utc>) {
-    let old_drone_docuents = load_replay_drones().unwrap_or_default();
     let drones = LATEST_DRONE_SNAPSHOT.lock().await;
     if let Some(drones) = &*drones {
-        if let Some(last_document) = old_drone_docuents.last() {
-            if last_document == &*drones {
-                debug!("No changes to drones, skipping");
-                return;
-            }
+        if let Err(e) = record_drones(drones, time) {
+            error!("Failed to record drones: {e}");
         }
-        ensure_dir_exists();
-        let filename = format!("drones-{}.xml", time.timestamp());
-        debug!("Saving drone document to {}", filename);
-        let path = std::path::Path::new(SAVE_DIR).join(filename);
-        std::fs::write(path, quick_xml::se::to_string(&*drones).unwrap()).unwrap();
     } else {
         debug!("No drones, skipping");
-        return;
     }
 }
 
-pub(crate) fn load_replay_drones() -> Option<Vec<DronesDocument>> {
+pub(crate) fn load_replay_drones() -> Result<Vec<DronesDocument>> {
     ensure_dir_exists();
-    // get all files in the replay directory beginning with "drones"
-    let mut files = std::fs::read_dir(SAVE_DIR)
-        .ok()?
+    load_drones(&replay_dir())
+}
+
+fn load_drones(dir: &Path) -> Result<Vec<DronesDocument>> {
+    let delta_path = dir.join(DELTA_FILE);
+    if delta_path.exists() {
+        let bytes = std::fs::read(&delta_path)
+            .with_context(|| format!("Can't read {}", delta_path.display()))?;
+        return codec::decode(&bytes)
+            .with_context(|| format!("Failed to decode {}", delta_path.display()));
+    }
+    // get all files in the directory beginning with "drones"
+    let mut files = std::fs::read_dir(dir)
+        .with_context(|| format!("Can't read {}", dir.display()))?
         .filter_map(|f| f.ok())
-        .filter(|f| f.file_name().to_str().unwrap().starts_with("drones"))
+        .filter(|f| {
+            let name = f.file_name();
+            let name = name.to_str().unwrap();
+            name.starts_with("drones") && name.ends_with(".xml")
+        })
         .collect::<Vec<_>>();
     // sort by filename
-    files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    files.sort_by_key(|f| f.file_name());
     // read all files
     let mut documents = vec![];
     for file in files {
-        let content = std::fs::read_to_string(file.path()).ok()?;
-        let doc: DronesDocument = quick_xml::de::from_str(&content).ok()?;
+        let path = file.path();
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Can't read {}", path.display()))?;
+        let doc: DronesDocument = quick_xml::de::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
         documents.push(doc);
     }
 
-    Some(documents)
+    Ok(documents)
 }
@@ -0,0 +1,216 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{debug, info};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use super::codec::Encoder;
+use super::session::{
+    save_manifest, session_arg, session_path, RecordingFormat, SessionManifest, DELTA_FILE,
+};
+use crate::{config::zones, reaktor::drones::DronesDocument};
+
+lazy_static! {
+    /// The recording in progress, --record starts one when the process starts
+    static ref RECORDING: Mutex<Option<Recording>> = Mutex::new(
+        std::env::args()
+            .any(|a| a == "--record")
+            .then(|| Recording::new(session_arg(), RecordingLimits::default()))
+    );
+}
+
+/// Optional conditions that end a recording automatically
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Apiv2Schema)]
+pub struct RecordingLimits {
+    /// Stop after this many seconds have passed since the first snapshot
+    pub duration_secs: Option<u64>,
+    /// Stop after this many snapshots have been saved
+    pub max_snapshots: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Clone, Apiv2Schema)]
+pub struct RecordingProgress {
+    /// The session being recorded into, null until the first snapshot has been saved
+    pub session: Option<String>,
+    /// RFC3339 time stamp of the first recorded snapshot
+    pub started_at: Option<String>,
+    pub snapshot_count: usize,
+    pub limits: RecordingLimits,
+}
+
+struct Recording {
+    /// Name requested for the session, otherwise one is generated from the first snapshot time
+    requested_id: Option<String>,
+    limits: RecordingLimits,
+    /// Created when the first snapshot is saved
+    manifest: Option<SessionManifest>,
+    encoder: Encoder,
+    /// Identical consecutive snapshots are only recorded once
+    last_snapshot: Option<DronesDocument>,
+}
+
+impl Recording {
+    fn new(requested_id: Option<String>, limits: RecordingLimits) -> Self {
+        Self {
+            requested_id,
+            limits,
+            manifest: None,
+            encoder: Encoder::default(),
+            last_snapshot: None,
+        }
+    }
+
+    fn progress(&self) -> RecordingProgress {
+        RecordingProgress {
+            session: self.manifest.as_ref().map(|m| m.id.clone()),
+            started_at: self.manifest.as_ref().map(|m| m.started_at.clone()),
+            snapshot_count: self.manifest.as_ref().map_or(0, |m| m.snapshot_count),
+            limits: self.limits.clone(),
+        }
+    }
+
+    fn limit_reached(&self, time: DateTime<Utc>) -> bool {
+        let Some(manifest) = &self.manifest else {
+            return false;
+        };
+        let snapshots_reached = self
+            .limits
+            .max_snapshots
+            .is_some_and(|max| manifest.snapshot_count >= max);
+        let duration_reached = self.limits.duration_secs.is_some_and(|secs| {
+            DateTime::parse_from_rfc3339(&manifest.started_at)
+                .is_ok_and(|start| (time - start.with_timezone(&Utc)).num_seconds() >= secs as i64)
+        });
+        snapshots_reached || duration_reached
+    }
+}
+
+pub fn is_recording() -> bool {
+    RECORDING.lock().unwrap().is_some()
+}
+
+pub fn recording_progress() -> Option<RecordingProgress> {
+    RECORDING.lock().unwrap().as_ref().map(Recording::progress)
+}
+
+pub fn recording_session_id() -> Option<String> {
+    RECORDING
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|r| r.manifest.as_ref())
+        .map(|m| m.id.clone())
+}
+
+/// Start recording snapshots into a new session
+pub fn start_recording(id: Option<String>, limits: RecordingLimits) -> Result<RecordingProgress> {
+    if super::is_replaying() {
+        bail!("Cannot record while replaying");
+    }
+    if let Some(id) = &id {
+        if session_path(id)?.exists() {
+            bail!("Session \"{id}\" already exists");
+        }
+    }
+    let mut recording = RECORDING.lock().unwrap();
+    if recording.is_some() {
+        bail!("A recording is already in progress");
+    }
+    info!("Starting a recording");
+    let new = Recording::new(id, limits);
+    let progress = new.progress();
+    *recording = Some(new);
+    Ok(progress)
+}
+
+/// Stop the recording in progress, returning the manifest of the finished session
+pub fn stop_recording() -> Result<Option<SessionManifest>> {
+    let recording = RECORDING
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| anyhow!("No recording in progress"))?;
+    info!("Stopped recording");
+    Ok(recording.manifest)
+}
+
+impl Recording {
+    /// The directory recorded snapshots and pilots are written to,
+    /// starting a new session on first use
+    fn session_dir(&mut self, time: DateTime<Utc>) -> Result<PathBuf> {
+        if self.manifest.is_none() {
+            let id = self
+                .requested_id
+                .clone()
+                .unwrap_or_else(|| format!("session-{}", time.timestamp()));
+            let path = session_path(&id)?;
+            if path.exists() {
+                bail!("Session \"{id}\" already exists, pick another name with --session");
+            }
+            info!("Recording into session {id}");
+            let manifest = SessionManifest {
+                id,
+                started_at: time.to_rfc3339(),
+                ended_at: time.to_rfc3339(),
+                device_id: None,
+                listen_range: None,
+                zones: zones(),
+                snapshot_count: 0,
+                software_version: env!("CARGO_PKG_VERSION").to_string(),
+                format: RecordingFormat::Delta,
+            };
+            save_manifest(&manifest)?;
+            self.manifest = Some(manifest);
+        }
+        let manifest = self.manifest.as_ref().expect("Session was created above");
+        session_path(&manifest.id)
+    }
+}
+
+/// Append a snapshot to the recording session and update its manifest,
+/// ending the recording once one of its limits is reached
+pub(crate) fn record_drones(doc: &DronesDocument, time: DateTime<Utc>) -> Result<()> {
+    let mut lock = RECORDING.lock().unwrap();
+    let recording = lock
+        .as_mut()
+        .ok_or_else(|| anyhow!("No recording in progress"))?;
+    if recording.last_snapshot.as_ref() == Some(doc) {
+        debug!("No changes to drones, skipping");
+        return Ok(());
+    }
+    let dir = recording.session_dir(time)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(DELTA_FILE))?;
+    // Later snapshots are encoded against this one, only if it made it into the file
+    let (bytes, encoder) = recording.encoder.encode(doc);
+    let written = file.metadata()?.len();
+    if let Err(e) = file.write_all(&bytes) {
+        // Drop a partly written snapshot, the next one is encoded against the previous one
+        file.set_len(written)?;
+        return Err(e.into());
+    }
+    recording.encoder = encoder;
+    recording.last_snapshot = Some(doc.clone());
+
+    let manifest = recording
+        .manifest
+        .as_mut()
+        .expect("Session was created by session_dir");
+    manifest.ended_at = time.to_rfc3339();
+    manifest.device_id = doc.device_information.device_id.clone();
+    manifest.listen_range = doc.device_information.listen_range;
+    manifest.snapshot_count += 1;
+    save_manifest(manifest)?;
+    if recording.limit_reached(time) {
+        info!("Recording limit reached, stopping the recording");
+        *lock = None;
+    }
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use log::info;
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use super::{recording::recording_session_id, SAVE_DIR};
+use crate::config::Zone;
+
+const MANIFEST_FILE: &str = "manifest.json";
+/// Snapshots of [RecordingFormat::Delta] sessions, see [super::codec]
+pub(crate) const DELTA_FILE: &str = "drones.bnr";
+
+lazy_static! {
+    /// The session that is replayed, [None] replays the files directly in [SAVE_DIR]
+    static ref SELECTED_SESSION: RwLock<Option<String>> = RwLock::new(session_arg());
+}
+
+/// Describes a single recording session, stored as `manifest.json` next to the recorded files
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Apiv2Schema)]
+pub struct SessionManifest {
+    /// Name of the session directory
+    pub id: String,
+    /// RFC3339 time stamp of the first recorded snapshot
+    pub started_at: String,
+    /// RFC3339 time stamp of the last recorded snapshot
+    pub ended_at: String,
+    pub device_id: Option<String>,
+    pub listen_range: Option<usize>,
+    /// The zones in use while recording
+    pub zones: Vec<Zone>,
+    pub snapshot_count: usize,
+    /// Version of birdnest-api that made the recording
+    pub software_version: String,
+    /// How the snapshots are stored, sessions recorded before this field existed are xml
+    #[serde(default)]
+    pub format: RecordingFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Apiv2Schema)]
+pub enum RecordingFormat {
+    /// Every snapshot in its own xml file
+    #[default]
+    Xml,
+    /// Every snapshot appended to a single file, storing only what changed
+    Delta,
+}
+
+/// Returns the value given to `--session`, or BIRDNEST_SESSION if the flag is missing
+pub(crate) fn session_arg() -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|a| a == "--session")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("BIRDNEST_SESSION").ok())
+}
+
+/// Session ids double as directory names, so only allow characters that are safe in a path
+fn validate_id(id: &str) -> Result<()> {
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!("Invalid session id \"{id}\", only letters, numbers, - and _ are allowed");
+    }
+    Ok(())
+}
+
+pub fn session_path(id: &str) -> Result<PathBuf> {
+    validate_id(id)?;
+    Ok(PathBuf::from(SAVE_DIR).join(id))
+}
+
+pub fn load_manifest(id: &str) -> Result<SessionManifest> {
+    let path = session_path(id)?.join(MANIFEST_FILE);
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Session \"{id}\" does not exist"))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub(crate) fn save_manifest(manifest: &SessionManifest) -> Result<()> {
+    let dir = session_path(&manifest.id)?;
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(
+        dir.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(manifest)?,
+    )?;
+    Ok(())
+}
+
+/// All sessions with a readable manifest, oldest first
+pub fn list_sessions() -> Vec<SessionManifest> {
+    let mut sessions: Vec<_> = std::fs::read_dir(SAVE_DIR)
+        .map(|dir| {
+            dir.filter_map(|f| f.ok())
+                .filter(|f| f.path().is_dir())
+                .filter_map(|f| load_manifest(f.file_name().to_str()?).ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    sessions.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+    sessions
+}
+
+pub fn delete_session(id: &str) -> Result<()> {
+    let manifest = load_manifest(id)?;
+    if recording_session_id().as_deref() == Some(id) {
+        bail!("Session \"{id}\" is still being recorded");
+    }
+    if selected_session().as_deref() == Some(id) {
+        select_session(None)?;
+    }
+    info!("Deleting session {}", manifest.id);
+    std::fs::remove_dir_all(session_path(id)?)?;
+    Ok(())
+}
+
+pub fn selected_session() -> Option<String> {
+    SELECTED_SESSION.read().unwrap().clone()
+}
+
+/// Choose the session to replay, [None] goes back to the files directly in [SAVE_DIR]
+pub fn select_session(id: Option<String>) -> Result<()> {
+    if let Some(id) = &id {
+        load_manifest(id)?;
+    }
+    info!("Selected session {:?} for replay", id);
+    *SELECTED_SESSION.write().unwrap() = id;
+    super::reset_replay_position();
+    Ok(())
+}
+
+/// The directory replayed snapshots and pilots are read from
+pub(crate) fn replay_dir() -> PathBuf {
+    match selected_session() {
+        Some(id) => session_path(&id).unwrap_or_else(|_| PathBuf::from(SAVE_DIR)),
+        None => PathBuf::from(SAVE_DIR),
+    }
+}
+
+/// Handles `birdnest-api sessions <command>`
+pub fn run_cli(args: &[String]) -> Result<()> {
+    match args.first().map(|a| a.as_str()) {
+        Some("list") | None => {
+            for s in list_sessions() {
+                println!(
+                    "{}\t{} - {}\t{} snapshots",
+                    s.id, s.started_at, s.ended_at, s.snapshot_count
+                );
+            }
+        }
+        Some("show") => {
+            let id = args.get(1).context("Usage: sessions show <id>")?;
+            println!("{}", serde_json::to_string_pretty(&load_manifest(id)?)?);
+        }
+        Some("delete") => {
+            let id = args.get(1).context("Usage: sessions delete <id>")?;
+            delete_session(id)?;
+            println!("Deleted session {id}");
+        }
+        Some(other) => bail!("Unknown command \"{other}\", expected list, show or delete"),
+    }
+    Ok(())
+}
@@ -0,0 +1,462 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use log::{error, info};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::INFRINGEMENTS_REVISION,
+    changes::CHANGES,
+    storage::{load_json, save_json},
+    Infringement,
+};
+
+/// How often reviews changed by [retain_evicted] are saved and old reviews forgotten
+const MAINTENANCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// A visit of a drone: its serial number and when the infringement started
+type ReviewKey = (String, DateTime<Utc>);
+
+lazy_static! {
+    /// Reviewed infringements by visit, loaded from [reviews_file] on first use
+    static ref REVIEWS: RwLock<BTreeMap<ReviewKey, ReviewedInfringement>> = RwLock::new(load());
+    /// How long reviews are kept after their infringement has left the cache,
+    /// read from BIRDNEST_REVIEW_RETENTION_DAYS
+    static ref RETENTION: Duration = std::env::var("BIRDNEST_REVIEW_RETENTION_DAYS")
+        .map(|v| Duration::days(v.parse().expect("Invalid BIRDNEST_REVIEW_RETENTION_DAYS")))
+        .unwrap_or(Duration::days(30));
+}
+
+/// Set when [REVIEWS] has changes that have not been saved yet
+static UNSAVED: AtomicBool = AtomicBool::new(false);
+
+/// Read from BIRDNEST_REVIEWS_FILE, defaults to `reviews.json`
+fn reviews_file() -> PathBuf {
+    PathBuf::from(
+        std::env::var("BIRDNEST_REVIEWS_FILE").unwrap_or_else(|_| "reviews.json".to_string()),
+    )
+}
+
+/// What operators have decided about an infringement
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewState {
+    /// Nobody has looked at it yet
+    #[default]
+    Open,
+    Acknowledged,
+    /// A false positive
+    Dismissed,
+    Escalated,
+}
+
+impl ReviewState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReviewState::Open => "open",
+            ReviewState::Acknowledged => "acknowledged",
+            ReviewState::Dismissed => "dismissed",
+            ReviewState::Escalated => "escalated",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewActionKind {
+    Acknowledge,
+    Dismiss,
+    Escalate,
+    /// Only adds a note, the state stays the same
+    Note,
+}
+
+impl ReviewActionKind {
+    fn state(&self) -> Option<ReviewState> {
+        match self {
+            ReviewActionKind::Acknowledge => Some(ReviewState::Acknowledged),
+            ReviewActionKind::Dismiss => Some(ReviewState::Dismissed),
+            ReviewActionKind::Escalate => Some(ReviewState::Escalated),
+            ReviewActionKind::Note => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct ReviewRequest {
+    pub action: ReviewActionKind,
+    /// Who made the change
+    #[openapi(example = "Jane Operator")]
+    pub by: String,
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct ReviewAction {
+    pub action: ReviewActionKind,
+    pub by: String,
+    pub at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct Review {
+    pub state: ReviewState,
+    pub updated_by: String,
+    pub updated_at: DateTime<Utc>,
+    /// Every change, oldest first
+    pub actions: Vec<ReviewAction>,
+}
+
+/// A review and the last known state of the infringement, so it outlives the cache
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ReviewedInfringement {
+    review: Review,
+    infringement: Infringement,
+    /// When the infringement left the cache, the retention starts from here
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    left_cache_at: Option<DateTime<Utc>>,
+}
+
+fn key_of(infringement: &Infringement) -> ReviewKey {
+    (
+        infringement.drone_serial_number.clone(),
+        infringement.first_seen_at,
+    )
+}
+
+fn load() -> BTreeMap<ReviewKey, ReviewedInfringement> {
+    let path = reviews_file();
+    let reviews = match load_json::<Vec<ReviewedInfringement>>(&path) {
+        Ok(reviews) => reviews.unwrap_or_default(),
+        Err(e) => panic!("Failed to load reviews from {}: {e}", path.display()),
+    };
+    info!("Loaded {} reviews from {}", reviews.len(), path.display());
+    let loaded_at = Utc::now();
+    reviews
+        .into_iter()
+        .map(|mut reviewed| {
+            // The cache does not survive a restart
+            reviewed.left_cache_at.get_or_insert(loaded_at);
+            (key_of(&reviewed.infringement), reviewed)
+        })
+        .collect()
+}
+
+fn save(reviews: &BTreeMap<ReviewKey, ReviewedInfringement>) -> Result<()> {
+    save_json(&reviews_file(), &reviews.values().collect::<Vec<_>>())
+}
+
+/// Load the reviews up front, so a broken reviews file stops the server from starting
+pub fn init() {
+    lazy_static::initialize(&REVIEWS);
+    lazy_static::initialize(&RETENTION);
+}
+
+/// The review of the visit an infringement belongs to. Its start can move earlier
+/// when an older snapshot is recorded late, so any review started during it matches
+fn find<'a>(
+    reviews: &'a BTreeMap<ReviewKey, ReviewedInfringement>,
+    infringement: &Infringement,
+) -> Option<(&'a ReviewKey, &'a ReviewedInfringement)> {
+    let serial = &infringement.drone_serial_number;
+    reviews
+        .range(
+            (serial.clone(), infringement.first_seen_at)
+                ..=(serial.clone(), infringement.last_seen_at),
+        )
+        .next_back()
+}
+
+/// The review of the visit starting at `first_seen_at`, or the latest reviewed visit
+fn lookup<'a>(
+    reviews: &'a BTreeMap<ReviewKey, ReviewedInfringement>,
+    drone_serial_number: &str,
+    first_seen_at: Option<DateTime<Utc>>,
+) -> Option<(&'a ReviewKey, &'a ReviewedInfringement)> {
+    let serial = drone_serial_number.to_string();
+    match first_seen_at {
+        Some(at) => reviews.get_key_value(&(serial, at)),
+        None => reviews
+            .range((serial.clone(), DateTime::<Utc>::MIN_UTC)..=(serial, DateTime::<Utc>::MAX_UTC))
+            .next_back(),
+    }
+}
+
+/// Record a review action. `infringement` is the current state of the infringement if it is
+/// still in the cache, otherwise the visit starting at `first_seen_at` is reviewed, by default
+/// the latest reviewed one
+pub fn review(
+    drone_serial_number: &str,
+    infringement: Option<Infringement>,
+    first_seen_at: Option<DateTime<Utc>>,
+    request: ReviewRequest,
+) -> Result<Review> {
+    if request.by.trim().is_empty() {
+        bail!("by can not be empty");
+    }
+    let mut reviews = REVIEWS.write().unwrap();
+    let previous = match &infringement {
+        Some(infringement) => find(&reviews, infringement),
+        None => lookup(&reviews, drone_serial_number, first_seen_at),
+    }
+    .map(|(key, reviewed)| (key.clone(), reviewed.clone()));
+    let left_cache_at = match &infringement {
+        Some(_) => None,
+        None => previous.as_ref().and_then(|(_, r)| r.left_cache_at),
+    };
+    let Some(infringement) =
+        infringement.or_else(|| previous.as_ref().map(|(_, r)| r.infringement.clone()))
+    else {
+        bail!("No infringement for drone {drone_serial_number}");
+    };
+    let now = Utc::now();
+    let mut review = previous
+        .as_ref()
+        .map(|(_, r)| r.review.clone())
+        .unwrap_or(Review {
+            state: ReviewState::Open,
+            updated_by: request.by.clone(),
+            updated_at: now,
+            actions: vec![],
+        });
+    if let Some(state) = request.action.state() {
+        review.state = state;
+    }
+    review.updated_by = request.by.clone();
+    review.updated_at = now;
+    review.actions.push(ReviewAction {
+        action: request.action,
+        by: request.by,
+        at: now,
+        note: request.note,
+    });
+    if let Some((key, _)) = &previous {
+        reviews.remove(key);
+    }
+    let key = key_of(&infringement);
+    reviews.insert(
+        key.clone(),
+        ReviewedInfringement {
+            review: review.clone(),
+            infringement: Infringement {
+                review: None,
+                ..infringement
+            },
+            left_cache_at,
+        },
+    );
+    if let Err(e) = save(&reviews) {
+        reviews.remove(&key);
+        if let Some((key, previous)) = previous {
+            reviews.insert(key, previous);
+        }
+        return Err(e);
+    }
+    Ok(review)
+}
+
+/// The review of `infringement` if it is in the cache, otherwise of the visit starting at
+/// `first_seen_at` or the latest reviewed visit
+pub fn get_review(
+    drone_serial_number: &str,
+    infringement: Option<&Infringement>,
+    first_seen_at: Option<DateTime<Utc>>,
+) -> Option<Review> {
+    let reviews = REVIEWS.read().unwrap();
+    match infringement {
+        Some(infringement) => find(&reviews, infringement),
+        None => lookup(&reviews, drone_serial_number, first_seen_at),
+    }
+    .map(|(_, r)| r.review.clone())
+}
+
+/// Called when an infringement leaves the cache. Reviewed infringements are kept with their
+/// review, returns whether it was reviewed. The change is saved by [run_maintenance],
+/// this runs inside the cache
+pub fn retain_evicted(infringement: &Infringement) -> bool {
+    let mut reviews = REVIEWS.write().unwrap();
+    let Some(key) = find(&reviews, infringement).map(|(key, _)| key.clone()) else {
+        return false;
+    };
+    let mut reviewed = reviews.remove(&key).expect("The key was just found");
+    reviewed.infringement = Infringement {
+        review: None,
+        ..infringement.clone()
+    };
+    reviewed.left_cache_at = Some(Utc::now());
+    reviews.insert(key_of(infringement), reviewed);
+    UNSAVED.store(true, Ordering::SeqCst);
+    true
+}
+
+/// Remove the reviews whose infringement left the cache before `forget_before`
+fn forget_expired(
+    reviews: &mut BTreeMap<ReviewKey, ReviewedInfringement>,
+    forget_before: DateTime<Utc>,
+) -> Vec<ReviewedInfringement> {
+    let expired: Vec<_> = reviews
+        .iter()
+        .filter(|(_, r)| r.left_cache_at.is_some_and(|at| at < forget_before))
+        .map(|(key, _)| key.clone())
+        .collect();
+    expired
+        .into_iter()
+        .filter_map(|key| reviews.remove(&key))
+        .collect()
+}
+
+/// Saves the changes of [retain_evicted] and forgets reviews whose infringement left
+/// the cache more than the retention ago
+pub async fn run_maintenance() {
+    loop {
+        tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+        let forget_before = Utc::now() - *RETENTION;
+        let forgotten = {
+            let mut reviews = REVIEWS.write().unwrap();
+            let forgotten = forget_expired(&mut reviews, forget_before);
+            // Saved while holding the lock, so an older state never replaces a newer one
+            if UNSAVED.swap(false, Ordering::SeqCst) || !forgotten.is_empty() {
+                if let Err(e) = save(&reviews) {
+                    error!("Failed to save reviews: {e}");
+                    UNSAVED.store(true, Ordering::SeqCst);
+                }
+            }
+            forgotten
+        };
+        if forgotten.is_empty() {
+            continue;
+        }
+        info!("Forgetting {} reviews past the retention", forgotten.len());
+        let mut changes = CHANGES.lock().unwrap();
+        for reviewed in &forgotten {
+            changes.delete(&reviewed.infringement);
+        }
+        drop(changes);
+        INFRINGEMENTS_REVISION.bump();
+    }
+}
+
+/// Attach the reviews to the infringements and add reviewed infringements that have
+/// already left the cache
+pub fn with_reviews(mut infringements: Vec<Infringement>) -> Vec<Infringement> {
+    let reviews = REVIEWS.read().unwrap();
+    let mut attached = HashSet::new();
+    for infringement in infringements.iter_mut() {
+        if let Some((key, reviewed)) = find(&reviews, infringement) {
+            infringement.review = Some(reviewed.review.clone());
+            attached.insert(key);
+        }
+    }
+    for (key, reviewed) in reviews.iter() {
+        if !attached.contains(key) {
+            infringements.push(Infringement {
+                review: Some(reviewed.review.clone()),
+                ..reviewed.infringement.clone()
+            });
+        }
+    }
+    infringements
+}
+
+/// Attach the review of a single infringement
+pub fn with_review(mut infringement: Infringement) -> Infringement {
+    infringement.review =
+        find(&REVIEWS.read().unwrap(), &infringement).map(|(_, r)| r.review.clone());
+    infringement
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::test_fixtures::infringement;
+
+    fn reviewed(
+        serial: &str,
+        first_seen_at: DateTime<Utc>,
+        left_cache_at: Option<DateTime<Utc>>,
+    ) -> ReviewedInfringement {
+        let infringement = Infringement {
+            last_seen_at: first_seen_at + Duration::minutes(1),
+            updated_at: first_seen_at + Duration::minutes(1),
+            ..infringement(serial, first_seen_at)
+        };
+        ReviewedInfringement {
+            review: Review {
+                state: ReviewState::Dismissed,
+                updated_by: "Operator".to_string(),
+                updated_at: first_seen_at,
+                actions: vec![],
+            },
+            infringement,
+            left_cache_at,
+        }
+    }
+
+    fn reviews(list: Vec<ReviewedInfringement>) -> BTreeMap<ReviewKey, ReviewedInfringement> {
+        list.into_iter()
+            .map(|r| (key_of(&r.infringement), r))
+            .collect()
+    }
+
+    #[test]
+    fn reviews_belong_to_a_single_visit() {
+        let morning = Utc.with_ymd_and_hms(2023, 6, 28, 8, 0, 0).unwrap();
+        let evening = Utc.with_ymd_and_hms(2023, 6, 28, 20, 0, 0).unwrap();
+        let reviews = reviews(vec![
+            reviewed("SN-1", morning, None),
+            reviewed("SN-2", evening, None),
+        ]);
+
+        // The same visit, even if an older snapshot moved its start
+        let mut visit = reviewed("SN-1", morning - Duration::seconds(2), None).infringement;
+        visit.last_seen_at = morning + Duration::minutes(5);
+        assert_eq!(
+            find(&reviews, &visit).unwrap().0,
+            &("SN-1".to_string(), morning)
+        );
+        // The drone came back in the evening
+        let return_visit = reviewed("SN-1", evening, None).infringement;
+        assert!(find(&reviews, &return_visit).is_none());
+
+        assert_eq!(lookup(&reviews, "SN-1", None).unwrap().0 .1, morning);
+        assert!(lookup(&reviews, "SN-1", Some(evening)).is_none());
+        assert_eq!(
+            lookup(&reviews, "SN-2", Some(evening)).unwrap().0 .1,
+            evening
+        );
+        assert!(lookup(&reviews, "SN-3", None).is_none());
+    }
+
+    #[test]
+    fn reviews_are_forgotten_after_the_retention() {
+        let now = Utc.with_ymd_and_hms(2023, 6, 28, 8, 0, 0).unwrap();
+        let mut reviews = reviews(vec![
+            reviewed("SN-cached", now - Duration::days(60), None),
+            reviewed(
+                "SN-old",
+                now - Duration::days(60),
+                Some(now - Duration::days(31)),
+            ),
+            reviewed(
+                "SN-recent",
+                now - Duration::days(60),
+                Some(now - Duration::days(29)),
+            ),
+        ]);
+        let forgotten = forget_expired(&mut reviews, now - Duration::days(30));
+        assert_eq!(forgotten.len(), 1);
+        assert_eq!(forgotten[0].infringement.drone_serial_number, "SN-old");
+        let kept: Vec<_> = reviews.keys().map(|(serial, _)| serial.as_str()).collect();
+        assert_eq!(kept, ["SN-cached", "SN-recent"]);
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use handlebars::{
+    handlebars_helper, Context as HandlebarsContext, Handlebars, Helper, HelperResult, Output,
+    RenderContext, RenderErrorReason,
+};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{zones, Tier, Zone},
+    events::{ZoneEvent, EVENTS},
+    features::{alerts::Alert, webhooks::WebhookEvent},
+    reaktor::pilots::Pilot,
+    Infringement,
+};
+
+/// The template directory is checked for changes at most this often
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Templates that are always there, files with the same name replace them
+const BUILTIN: &[(&str, &str)] = &[
+    ("email/subject", include_str!("builtin/email/subject.hbs")),
+    ("email/body", include_str!("builtin/email/body.hbs")),
+    (
+        "email/alert_subject",
+        include_str!("builtin/email/alert_subject.hbs"),
+    ),
+    (
+        "email/alert_body",
+        include_str!("builtin/email/alert_body.hbs"),
+    ),
+    (
+        "email/infringement",
+        include_str!("builtin/email/infringement.hbs"),
+    ),
+];
+
+lazy_static! {
+    static ref BUILTIN_REGISTRY: Handlebars<'static> = load(None, &BTreeMap::new(), Escape::None)
+        .unwrap_or_else(|e| panic!("Invalid built-in template: {e}"));
+    static ref TEMPLATES: RwLock<Templates> = RwLock::new(Templates::from_dir(templates_dir()));
+}
+
+/// Read from --templates or BIRDNEST_TEMPLATES, only the built-in templates are used without it
+fn templates_dir() -> Option<PathBuf> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|a| a == "--templates")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("BIRDNEST_TEMPLATES").ok())
+        .map(PathBuf::from)
+}
+
+/// How values are escaped in the output of a template
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum Escape {
+    /// Plain text, for emails
+    None,
+    /// Values are escaped for json strings, for webhook bodies
+    Json,
+}
+
+impl Escape {
+    /// Templates under `email/` are plain text, the rest are webhook bodies
+    pub fn of(name: &str) -> Self {
+        match name.starts_with("email/") {
+            true => Escape::None,
+            false => Escape::Json,
+        }
+    }
+}
+
+/// Quotes, backslashes and control characters escaped, without the surrounding quotes
+fn escape_json(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// `{{json value}}` writes the value as json, it is not escaped again
+fn json(
+    h: &Helper,
+    _: &Handlebars,
+    _: &HandlebarsContext,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = h
+        .param(0)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("json", 0))?
+        .value();
+    out.write(&serde_json::to_string(value).unwrap_or_default())?;
+    Ok(())
+}
+
+handlebars_helper!(meters: |distance: f64| format!("{:.1}", distance / 1000.0));
+handlebars_helper!(round: |value: f64, {digits: u64 = 0}| format!("{value:.0$}", digits as usize));
+handlebars_helper!(time: |value: str| match value.parse::<DateTime<Utc>>() {
+    Ok(parsed) => parsed.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+    Err(_) => value.to_string(),
+});
+
+/// The built-in templates and the `.hbs` files, named by their path in the directory
+/// without the extension
+fn load(
+    dir: Option<&Path>,
+    files: &BTreeMap<PathBuf, SystemTime>,
+    escape: Escape,
+) -> Result<Handlebars<'static>> {
+    let mut registry = Handlebars::new();
+    match escape {
+        Escape::None => registry.register_escape_fn(handlebars::no_escape),
+        Escape::Json => registry.register_escape_fn(escape_json),
+    }
+    registry.register_helper("json", Box::new(json));
+    registry.register_helper("meters", Box::new(meters));
+    registry.register_helper("round", Box::new(round));
+    registry.register_helper("time", Box::new(time));
+    for (name, template) in BUILTIN {
+        registry.register_template_string(name, template)?;
+    }
+    if let Some(dir) = dir {
+        for path in files.keys() {
+            let template = std::fs::read_to_string(path)
+                .with_context(|| format!("Can't read {}", path.display()))?;
+            // The errors of handlebars repeat their cause, keep only the message
+            registry
+                .register_template_string(&template_name(dir, path), template)
+                .map_err(|e| anyhow!("{}: {e}", path.display()))?;
+        }
+    }
+    Ok(registry)
+}
+
+fn template_name(dir: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(dir).unwrap_or(path).with_extension("");
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Every `.hbs` file in the directory and its subdirectories, with when it was modified
+fn scan(dir: &Path) -> Result<BTreeMap<PathBuf, SystemTime>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in
+            std::fs::read_dir(&dir).with_context(|| format!("Can't read {}", dir.display()))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|e| e == "hbs") {
+                files.insert(path, metadata.modified()?);
+            }
+        }
+    }
+    Ok(files)
+}
+
+struct Templates {
+    dir: Option<PathBuf>,
+    registry: Handlebars<'static>,
+    /// The same templates with [Escape::Json]
+    json_registry: Handlebars<'static>,
+    /// The files the registry was last loaded from
+    files: BTreeMap<PathBuf, SystemTime>,
+    checked_at: Instant,
+}
+
+impl Templates {
+    /// A broken template directory stops the server from starting
+    fn from_dir(dir: Option<PathBuf>) -> Self {
+        let files = match &dir {
+            Some(dir) => scan(dir)
+                .unwrap_or_else(|e| panic!("Failed to load templates from {}: {e}", dir.display())),
+            None => BTreeMap::new(),
+        };
+        let (registry, json_registry) = load_both(dir.as_deref(), &files)
+            .unwrap_or_else(|e| panic!("Failed to load templates: {e:#}"));
+        if let Some(dir) = &dir {
+            info!("Loaded {} templates from {}", files.len(), dir.display());
+        }
+        Self {
+            dir,
+            registry,
+            json_registry,
+            files,
+            checked_at: Instant::now(),
+        }
+    }
+
+    /// Reload the templates if a file was added, changed or removed.
+    /// Broken templates are logged and the previous ones kept
+    fn reload(&mut self) {
+        let Some(dir) = self.dir.clone() else {
+            return;
+        };
+        self.checked_at = Instant::now();
+        let files = match scan(&dir) {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Failed to check the templates for changes: {e}");
+                return;
+            }
+        };
+        if files == self.files {
+            return;
+        }
+        match load_both(Some(&dir), &files) {
+            Ok((registry, json_registry)) => {
+                info!("Reloaded {} templates from {}", files.len(), dir.display());
+                self.registry = registry;
+                self.json_registry = json_registry;
+            }
+            Err(e) => error!("Keeping the previous templates, failed to reload them: {e:#}"),
+        }
+        // Not retried until the files change again
+        self.files = files;
+    }
+
+    fn registry(&self, escape: Escape) -> &Handlebars<'static> {
+        match escape {
+            Escape::None => &self.registry,
+            Escape::Json => &self.json_registry,
+        }
+    }
+}
+
+fn load_both(
+    dir: Option<&Path>,
+    files: &BTreeMap<PathBuf, SystemTime>,
+) -> Result<(Handlebars<'static>, Handlebars<'static>)> {
+    Ok((
+        load(dir, files, Escape::None)?,
+        load(dir, files, Escape::Json)?,
+    ))
+}
+
+/// The templates, reloaded first if they have not been checked for a while
+fn templates() -> std::sync::RwLockReadGuard<'static, Templates> {
+    let stale = TEMPLATES.read().unwrap().checked_at.elapsed() >= RELOAD_INTERVAL;
+    if stale {
+        let mut templates = TEMPLATES.write().unwrap();
+        if templates.checked_at.elapsed() >= RELOAD_INTERVAL {
+            templates.reload();
+        }
+    }
+    TEMPLATES.read().unwrap()
+}
+
+/// Load the templates up front, so a broken template stops the server from starting
+pub fn init() {
+    lazy_static::initialize(&BUILTIN_REGISTRY);
+    lazy_static::initialize(&TEMPLATES);
+}
+
+/// What templates are rendered with
+#[derive(Serialize, Debug, Clone, Apiv2Schema)]
+pub struct TemplateContext {
+    pub event: WebhookEvent,
+    /// The tier before the change, only in infringement.tier_changed
+    pub previous_tier: Option<Tier>,
+    pub infringement: Option<Infringement>,
+    /// The pilot of the infringement
+    pub pilot: Option<Pilot>,
+    /// The zone of the infringement, missing if it was removed from the config
+    pub zone: Option<Zone>,
+    /// Zone entries and exits of the drone during the infringement, oldest first
+    pub track: Vec<ZoneEvent>,
+    pub alert: Option<Alert>,
+    /// The X-Birdnest-Delivery of a webhook
+    pub delivery_id: Option<u64>,
+}
+
+impl TemplateContext {
+    /// The context of an infringement event, pass the infringement redacted as needed
+    pub fn infringement(
+        event: WebhookEvent,
+        infringement: Infringement,
+        previous_tier: Option<Tier>,
+    ) -> Self {
+        let track = EVENTS
+            .lock()
+            .unwrap()
+            .since(0)
+            .filter(|e| e.drone_serial_number == infringement.drone_serial_number)
+            .filter(|e| e.at >= infringement.first_seen_at)
+            .cloned()
+            .collect();
+        Self {
+            event,
+            previous_tier,
+            pilot: infringement.pilot.clone(),
+            zone: zones().into_iter().find(|z| z.id == infringement.zone),
+            track,
+            infringement: Some(infringement),
+            alert: None,
+            delivery_id: None,
+        }
+    }
+
+    pub fn alert(alert: Alert) -> Self {
+        Self {
+            event: WebhookEvent::AlertFired,
+            previous_tier: None,
+            infringement: None,
+            pilot: None,
+            zone: None,
+            track: vec![],
+            alert: Some(alert),
+            delivery_id: None,
+        }
+    }
+}
+
+pub fn exists(name: &str) -> bool {
+    templates().registry.has_template(name)
+}
+
+/// Render a template, escaped as [Escape::of] its name. If a template from a file fails,
+/// the built-in one with the same name is used instead
+pub fn render(name: &str, context: &TemplateContext) -> Result<String> {
+    let result = templates().registry(Escape::of(name)).render(name, context);
+    match result {
+        Ok(output) => Ok(output),
+        Err(e) if BUILTIN_REGISTRY.has_template(name) => {
+            error!("Failed to render template {name}, using the built-in one: {e}");
+            Ok(BUILTIN_REGISTRY.render(name, context)?)
+        }
+        Err(e) => Err(anyhow!("Failed to render template {name}: {e}")),
+    }
+}
+
+/// Render a template that is not loaded, it can use the loaded ones as partials
+pub fn render_source(source: &str, escape: Escape, context: &TemplateContext) -> Result<String> {
+    let result = templates()
+        .registry(escape)
+        .render_template(source, context);
+    result.map_err(|e| anyhow!("{e}"))
+}
+
+#[derive(Serialize, Debug, Clone, Apiv2Schema)]
+pub struct TemplateInfo {
+    #[openapi(example = "email/body")]
+    pub name: String,
+    /// Replaced by a file
+    pub from_file: bool,
+}
+
+/// The loaded templates by name
+pub fn list_templates() -> Vec<TemplateInfo> {
+    let templates = templates();
+    let mut list: Vec<_> = templates
+        .registry
+        .get_templates()
+        .keys()
+        .map(|name| TemplateInfo {
+            name: name.clone(),
+            from_file: templates.dir.as_deref().is_some_and(|dir| {
+                templates
+                    .files
+                    .keys()
+                    .any(|path| &template_name(dir, path) == name)
+            }),
+        })
+        .collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn render(source: &str, escape: Escape) -> String {
+        load(None, &BTreeMap::new(), escape)
+            .unwrap()
+            .render_template(
+                source,
+                &json!({ "name": "Drone \"Ace\"\n", "pilot": { "id": "P-\"1\"" } }),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn webhook_templates_write_valid_json() {
+        let output = render(
+            r#"{"text": "{{name}}", "pilot": {{json pilot}}}"#,
+            Escape::Json,
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed["text"], "Drone \"Ace\"\n");
+        assert_eq!(parsed["pilot"]["id"], "P-\"1\"");
+    }
+
+    #[test]
+    fn email_templates_are_not_escaped() {
+        assert_eq!(render("{{name}}", Escape::None), "Drone \"Ace\"\n");
+        assert_eq!(Escape::of("email/body"), Escape::None);
+        assert_eq!(Escape::of("webhooks/slack"), Escape::Json);
+    }
+}
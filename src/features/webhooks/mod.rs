@@ -0,0 +1,745 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    config::Tier,
+    features::{
+        alerts::Alert,
+        privacy::{redact_infringement, redact_infringements},
+        templates::{self, TemplateContext},
+    },
+    server::auth::{Scope, Scopes},
+    storage::{load_json, save_json},
+    Infringement, InfringementStatus,
+};
+
+/// Finished deliveries kept for /webhooks/deliveries, the oldest are dropped first
+const FINISHED_DELIVERIES_KEPT: usize = 1000;
+/// The first retry waits this long, every retry after it twice as long as the one before
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    /// Webhooks from the config and the ones added through the api
+    static ref WEBHOOKS: RwLock<Vec<Webhook>> = RwLock::new(load_webhooks());
+    /// Deliveries waiting to be sent and the latest finished ones
+    static ref OUTBOX: Mutex<Outbox> = Mutex::new(load_outbox());
+    static ref MAX_ATTEMPTS: u32 = match std::env::var("BIRDNEST_WEBHOOK_MAX_ATTEMPTS") {
+        Ok(value) => match value.parse() {
+            Ok(attempts) if attempts > 0 => attempts,
+            _ => panic!("Invalid BIRDNEST_WEBHOOK_MAX_ATTEMPTS: {value}, expected at least 1"),
+        },
+        Err(_) => 8,
+    };
+}
+
+/// Load the webhooks, the outbox and the options on startup instead of on the first event
+pub fn init() {
+    lazy_static::initialize(&WEBHOOKS);
+    lazy_static::initialize(&OUTBOX);
+    lazy_static::initialize(&MAX_ATTEMPTS);
+}
+
+/// Read from BIRDNEST_WEBHOOKS_FILE, defaults to `webhooks.json`
+fn webhooks_file() -> PathBuf {
+    PathBuf::from(
+        std::env::var("BIRDNEST_WEBHOOKS_FILE").unwrap_or_else(|_| "webhooks.json".to_string()),
+    )
+}
+
+/// Read from BIRDNEST_WEBHOOK_OUTBOX_FILE, defaults to `webhook_outbox.json`
+fn outbox_file() -> PathBuf {
+    PathBuf::from(
+        std::env::var("BIRDNEST_WEBHOOK_OUTBOX_FILE")
+            .unwrap_or_else(|_| "webhook_outbox.json".to_string()),
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+pub enum WebhookEvent {
+    /// A drone entered a zone
+    #[serde(rename = "infringement.created")]
+    InfringementCreated,
+    /// An infringement reached a worse tier
+    #[serde(rename = "infringement.tier_changed")]
+    InfringementTierChanged,
+    /// An authorized drone became infringing, its exemption expired or it entered
+    /// a zone it is not exempt in
+    #[serde(rename = "infringement.status_changed")]
+    InfringementStatusChanged,
+    /// The drone has not been seen inside a zone for the infringement duration
+    #[serde(rename = "infringement.ended")]
+    InfringementEnded,
+    /// An alert rule fired
+    #[serde(rename = "alert.fired")]
+    AlertFired,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::InfringementCreated => "infringement.created",
+            WebhookEvent::InfringementTierChanged => "infringement.tier_changed",
+            WebhookEvent::InfringementStatusChanged => "infringement.status_changed",
+            WebhookEvent::InfringementEnded => "infringement.ended",
+            WebhookEvent::AlertFired => "alert.fired",
+        }
+    }
+}
+
+/// A webhook without the fields the server fills in
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct NewWebhook {
+    #[openapi(example = "https://example.com/birdnest")]
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature in X-Birdnest-Signature, at least 16 characters.
+    /// Never returned by the api
+    pub secret: String,
+    /// Events to send, every event if empty
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    /// Also send events about authorized drones
+    #[serde(default)]
+    pub include_authorized: bool,
+    /// Send unredacted pilot contact details
+    #[serde(default)]
+    pub include_pii: bool,
+    /// Template that renders the body instead of the default json payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[openapi(example = "webhooks/slack")]
+    pub template: Option<String>,
+    pub note: Option<String>,
+}
+
+// The fields of NewWebhook are written out, paperclip leaves flattened fields out of the
+// OpenAPI spec
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct Webhook {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    /// Webhooks from the config can't be deleted through the api
+    #[serde(default)]
+    pub from_config: bool,
+    #[openapi(example = "https://example.com/birdnest")]
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature in X-Birdnest-Signature, never returned by the api
+    pub secret: String,
+    /// Events to send, every event if empty
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    /// Also send events about authorized drones
+    #[serde(default)]
+    pub include_authorized: bool,
+    /// Send unredacted pilot contact details
+    #[serde(default)]
+    pub include_pii: bool,
+    /// Template that renders the body instead of the default json payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[openapi(example = "webhooks/slack")]
+    pub template: Option<String>,
+    pub note: Option<String>,
+}
+
+impl Webhook {
+    fn new(id: String, from_config: bool, webhook: NewWebhook) -> Self {
+        Self {
+            id,
+            created_at: Utc::now(),
+            from_config,
+            url: webhook.url,
+            secret: webhook.secret,
+            events: webhook.events,
+            include_authorized: webhook.include_authorized,
+            include_pii: webhook.include_pii,
+            template: webhook.template,
+            note: webhook.note,
+        }
+    }
+
+    fn wants(&self, event: WebhookEvent, infringement: &Infringement) -> bool {
+        (self.events.is_empty() || self.events.contains(&event))
+            && match infringement.status {
+                InfringementStatus::Infringing => true,
+                InfringementStatus::Authorized => self.include_authorized,
+                InfringementStatus::Approaching => false,
+            }
+    }
+
+    /// The webhook as the api returns it
+    fn without_secret(&self) -> Self {
+        let mut webhook = self.clone();
+        webhook.secret = "********".to_string();
+        webhook
+    }
+}
+
+/// A webhook in the file given with --webhooks or BIRDNEST_WEBHOOKS
+#[derive(Deserialize)]
+struct ConfigWebhook {
+    id: String,
+    #[serde(flatten)]
+    webhook: NewWebhook,
+}
+
+fn validate(webhook: &NewWebhook) -> Result<()> {
+    if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+        bail!("url has to be a http or https url");
+    }
+    if webhook.secret.len() < 16 {
+        bail!("secret has to be at least 16 characters");
+    }
+    if let Some(template) = &webhook.template {
+        if !templates::exists(template) {
+            bail!("There is no template {template}");
+        }
+    }
+    Ok(())
+}
+
+fn load_webhooks() -> Vec<Webhook> {
+    let path = webhooks_file();
+    let mut webhooks = match load_json::<Vec<Webhook>>(&path) {
+        Ok(webhooks) => webhooks.unwrap_or_default(),
+        Err(e) => panic!("Failed to load webhooks from {}: {e}", path.display()),
+    };
+    let args: Vec<String> = std::env::args().collect();
+    let config = args
+        .iter()
+        .position(|a| a == "--webhooks")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("BIRDNEST_WEBHOOKS").ok());
+    if let Some(config) = config {
+        let configured: Vec<ConfigWebhook> = std::fs::read_to_string(&config)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(serde_json::from_str(&json)?))
+            .unwrap_or_else(|e| panic!("Failed to load webhooks from {config}: {e}"));
+        for c in configured {
+            if let Err(e) = validate(&c.webhook) {
+                panic!("Invalid webhook {} in {config}: {e}", c.id);
+            }
+            webhooks.push(Webhook::new(c.id, true, c.webhook));
+        }
+    }
+    info!("Loaded {} webhooks", webhooks.len());
+    webhooks
+}
+
+/// Only the webhooks added through the api are saved, the config is read on every start
+fn save_webhooks(webhooks: &[Webhook]) -> Result<()> {
+    let added: Vec<_> = webhooks.iter().filter(|w| !w.from_config).collect();
+    save_json(&webhooks_file(), &added)
+}
+
+pub fn list_webhooks() -> Vec<Webhook> {
+    WEBHOOKS
+        .read()
+        .unwrap()
+        .iter()
+        .map(Webhook::without_secret)
+        .collect()
+}
+
+/// Unredacted pilot details can only be sent by callers that can read them
+pub fn add_webhook(webhook: NewWebhook, caller: &Scopes) -> Result<Webhook> {
+    validate(&webhook)?;
+    if webhook.include_pii && !caller.has(Scope::ReadPii) {
+        bail!("include_pii requires the {} scope", Scope::ReadPii);
+    }
+    let mut webhooks = WEBHOOKS.write().unwrap();
+    let next = webhooks
+        .iter()
+        .filter_map(|w| w.id.strip_prefix("wh-")?.parse::<u64>().ok())
+        .max()
+        .unwrap_or_default()
+        + 1;
+    let webhook = Webhook::new(format!("wh-{next}"), false, webhook);
+    webhooks.push(webhook.clone());
+    if let Err(e) = save_webhooks(&webhooks) {
+        webhooks.pop();
+        return Err(e);
+    }
+    Ok(webhook.without_secret())
+}
+
+pub fn delete_webhook(id: &str) -> Result<()> {
+    let mut webhooks = WEBHOOKS.write().unwrap();
+    let Some(index) = webhooks.iter().position(|w| w.id == id) else {
+        bail!("No webhook with id {id}");
+    };
+    if webhooks[index].from_config {
+        bail!("Webhook {id} is defined in the config");
+    }
+    let removed = webhooks.remove(index);
+    if let Err(e) = save_webhooks(&webhooks) {
+        webhooks.insert(index, removed);
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Waiting for its first attempt or a retry
+    Pending,
+    Delivered,
+    /// Every attempt failed
+    Failed,
+}
+
+/// A single event sent to a single webhook
+#[derive(Serialize, Deserialize, Debug, Clone, Apiv2Schema)]
+pub struct Delivery {
+    pub id: u64,
+    pub webhook_id: String,
+    pub event: WebhookEvent,
+    pub status: DeliveryStatus,
+    /// The signed json body, pilot details are redacted without read:pii
+    pub body: String,
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+    /// When the next attempt is made, missing once the delivery is finished
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    /// Status code of the last attempt, missing if there was no response
+    pub last_status_code: Option<u16>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Outbox {
+    next_id: u64,
+    deliveries: VecDeque<Delivery>,
+    /// Bodies without pilot details by delivery id, for the deliveries of webhooks with
+    /// include_pii
+    #[serde(default)]
+    redacted_bodies: HashMap<u64, String>,
+    /// Changed since the last save, saved by [run_deliveries] instead of on every event
+    #[serde(skip)]
+    unsaved: bool,
+}
+
+impl Outbox {
+    fn save(&mut self) {
+        match save_json(&outbox_file(), self) {
+            Ok(()) => self.unsaved = false,
+            Err(e) => error!("Failed to save the webhook outbox: {e}"),
+        }
+    }
+
+    /// Fail the pending deliveries of webhooks that no longer exist instead of retrying them
+    fn fail_orphaned(&mut self, webhooks: &[Webhook]) {
+        for delivery in self.deliveries.iter_mut() {
+            if delivery.status == DeliveryStatus::Pending
+                && !webhooks.iter().any(|w| w.id == delivery.webhook_id)
+            {
+                delivery.status = DeliveryStatus::Failed;
+                delivery.next_attempt_at = None;
+                delivery.last_error = Some("The webhook has been deleted".to_string());
+                self.unsaved = true;
+            }
+        }
+    }
+
+    /// Drop the oldest finished deliveries over [FINISHED_DELIVERIES_KEPT]
+    fn trim(&mut self) {
+        let finished = self
+            .deliveries
+            .iter()
+            .filter(|d| d.status != DeliveryStatus::Pending)
+            .count();
+        let mut extra = finished.saturating_sub(FINISHED_DELIVERIES_KEPT);
+        self.deliveries.retain(|d| {
+            let drop = extra > 0 && d.status != DeliveryStatus::Pending;
+            extra -= usize::from(drop);
+            !drop
+        });
+        let kept: HashSet<u64> = self.deliveries.iter().map(|d| d.id).collect();
+        self.redacted_bodies.retain(|id, _| kept.contains(id));
+    }
+
+    /// The delivery as the api returns it, with the redacted body unless the caller can read
+    /// pilot details
+    fn for_caller(&self, delivery: &Delivery, pii: bool) -> Delivery {
+        let mut delivery = delivery.clone();
+        if let Some(redacted) = self.redacted_bodies.get(&delivery.id).filter(|_| !pii) {
+            delivery.body = redacted.clone();
+        }
+        delivery
+    }
+}
+
+fn load_outbox() -> Outbox {
+    let path = outbox_file();
+    match load_json::<Outbox>(&path) {
+        Ok(outbox) => {
+            let outbox = outbox.unwrap_or_default();
+            let pending = outbox
+                .deliveries
+                .iter()
+                .filter(|d| d.status == DeliveryStatus::Pending)
+                .count();
+            info!("{pending} webhook deliveries pending in {}", path.display());
+            outbox
+        }
+        Err(e) => panic!(
+            "Failed to load the webhook outbox from {}: {e}",
+            path.display()
+        ),
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: u64,
+    event: WebhookEvent,
+    created_at: DateTime<Utc>,
+    /// The tier before the change, only in infringement.tier_changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_tier: Option<Tier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    infringement: Option<&'a Infringement>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alert: Option<&'a Alert>,
+}
+
+/// Queue the event for every webhook that wants it
+pub fn notify(event: WebhookEvent, infringement: &Infringement, previous_tier: Option<Tier>) {
+    let mut redacted = infringement.clone();
+    redact_infringement(&mut redacted);
+    enqueue(
+        event,
+        |w| w.wants(event, infringement),
+        |pii, payload| Payload {
+            previous_tier,
+            infringement: Some(match pii {
+                true => infringement,
+                false => &redacted,
+            }),
+            ..payload
+        },
+    );
+}
+
+/// Queue a fired alert for every webhook subscribed to alert.fired
+pub fn notify_alert(alert: &Alert) {
+    let event = WebhookEvent::AlertFired;
+    let mut redacted = alert.clone();
+    redact_infringements(&mut redacted.infringements);
+    enqueue(
+        event,
+        |w| w.events.is_empty() || w.events.contains(&event),
+        |pii, payload| Payload {
+            alert: Some(match pii {
+                true => alert,
+                false => &redacted,
+            }),
+            ..payload
+        },
+    );
+}
+
+/// The json payload, or the template of the webhook rendered with it
+fn render_body(webhook: &Webhook, payload: &Payload) -> Result<String> {
+    let Some(template) = &webhook.template else {
+        return Ok(serde_json::to_string(payload)?);
+    };
+    let mut context = match (payload.infringement, payload.alert) {
+        (Some(infringement), _) => TemplateContext::infringement(
+            payload.event,
+            infringement.clone(),
+            payload.previous_tier,
+        ),
+        (None, Some(alert)) => TemplateContext::alert(alert.clone()),
+        (None, None) => bail!("The payload has neither an infringement nor an alert"),
+    };
+    context.delivery_id = Some(payload.id);
+    templates::render(template, &context)
+}
+
+/// Add a delivery to the outbox for every webhook `wants` accepts, with the body `payload`
+/// fills in, with or without pilot details
+fn enqueue<'a>(
+    event: WebhookEvent,
+    wants: impl Fn(&Webhook) -> bool,
+    payload: impl Fn(bool, Payload<'a>) -> Payload<'a>,
+) {
+    let webhooks = WEBHOOKS.read().unwrap();
+    let targets: Vec<_> = webhooks.iter().filter(|w| wants(w)).collect();
+    if targets.is_empty() {
+        return;
+    }
+    let mut outbox = OUTBOX.lock().unwrap();
+    let now = Utc::now();
+    for webhook in targets {
+        outbox.next_id += 1;
+        let (delivery, redacted) = new_delivery(outbox.next_id, webhook, event, now, &payload);
+        if let Some(redacted) = redacted {
+            outbox.redacted_bodies.insert(delivery.id, redacted);
+        }
+        outbox.deliveries.push_back(delivery);
+    }
+    outbox.unsaved = true;
+}
+
+/// A pending delivery of the event to the webhook, with a redacted body for callers without
+/// read:pii if the webhook gets pilot details
+fn new_delivery<'a>(
+    id: u64,
+    webhook: &Webhook,
+    event: WebhookEvent,
+    now: DateTime<Utc>,
+    payload: impl Fn(bool, Payload<'a>) -> Payload<'a>,
+) -> (Delivery, Option<String>) {
+    let render = |pii| {
+        let payload = payload(
+            pii,
+            Payload {
+                id,
+                event,
+                created_at: now,
+                previous_tier: None,
+                infringement: None,
+                alert: None,
+            },
+        );
+        render_body(webhook, &payload)
+    };
+    let mut delivery = Delivery {
+        id,
+        webhook_id: webhook.id.clone(),
+        event,
+        status: DeliveryStatus::Pending,
+        body: String::new(),
+        attempts: 0,
+        created_at: now,
+        next_attempt_at: Some(now),
+        delivered_at: None,
+        last_status_code: None,
+        last_error: None,
+    };
+    let rendered = render(webhook.include_pii).and_then(|body| {
+        let redacted = webhook.include_pii.then(|| render(false)).transpose()?;
+        Ok((body, redacted))
+    });
+    // A body that can't be rendered is listed as failed instead of being dropped
+    match rendered {
+        Ok((body, redacted)) => {
+            delivery.body = body;
+            return (delivery, redacted);
+        }
+        Err(e) => {
+            error!(
+                "Failed to render a payload for webhook {}: {e:#}",
+                webhook.id
+            );
+            delivery.status = DeliveryStatus::Failed;
+            delivery.next_attempt_at = None;
+            delivery.last_error = Some(format!("{e:#}"));
+        }
+    }
+    (delivery, None)
+}
+
+/// Deliveries newest first, with pilot details only if `pii` is set
+pub fn list_deliveries(
+    webhook_id: Option<&str>,
+    status: Option<DeliveryStatus>,
+    limit: usize,
+    pii: bool,
+) -> Vec<Delivery> {
+    let outbox = OUTBOX.lock().unwrap();
+    outbox
+        .deliveries
+        .iter()
+        .rev()
+        .filter(|d| webhook_id.is_none_or(|id| d.webhook_id == id))
+        .filter(|d| status.is_none_or(|status| d.status == status))
+        .take(limit)
+        .map(|d| outbox.for_caller(d, pii))
+        .collect()
+}
+
+/// Hex encoded HMAC-SHA256 of the body, sent as `sha256=<signature>`
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn send(client: &reqwest::Client, delivery: &Delivery) -> Result<reqwest::StatusCode> {
+    let secret = WEBHOOKS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|w| w.id == delivery.webhook_id)
+        .map(|w| (w.url.clone(), w.secret.clone()));
+    let (url, secret) = secret.context("The webhook has been deleted")?;
+    let response = client
+        .post(url)
+        .header("content-type", "application/json")
+        .header("x-birdnest-event", delivery.event.as_str())
+        .header("x-birdnest-delivery", delivery.id.to_string())
+        .header(
+            "x-birdnest-signature",
+            format!("sha256={}", sign(&secret, &delivery.body)),
+        )
+        .body(delivery.body.clone())
+        .send()
+        .await?;
+    Ok(response.status())
+}
+
+/// Exponential backoff between [RETRY_BASE_DELAY] and [RETRY_MAX_DELAY]
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(RETRY_MAX_DELAY)
+}
+
+/// Send the due deliveries of the outbox, forever
+pub async fn run_deliveries() {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build the webhook client");
+    init();
+    loop {
+        let now = Utc::now();
+        let due: Vec<Delivery> = {
+            let webhooks = WEBHOOKS.read().unwrap();
+            let mut outbox = OUTBOX.lock().unwrap();
+            outbox.fail_orphaned(&webhooks);
+            if outbox.unsaved {
+                outbox.save();
+            }
+            outbox
+                .deliveries
+                .iter()
+                .filter(|d| d.status == DeliveryStatus::Pending)
+                .filter(|d| d.next_attempt_at.is_none_or(|at| at <= now))
+                .cloned()
+                .collect()
+        };
+        if !due.is_empty() {
+            let results = futures::future::join_all(due.iter().map(|d| send(&client, d))).await;
+            let mut outbox = OUTBOX.lock().unwrap();
+            let now = Utc::now();
+            for (sent, result) in due.iter().zip(results) {
+                let Some(delivery) = outbox.deliveries.iter_mut().find(|d| d.id == sent.id) else {
+                    continue;
+                };
+                delivery.attempts += 1;
+                let error = match &result {
+                    Ok(status) if status.is_success() => None,
+                    Ok(status) => Some(format!("Responded with {status}")),
+                    Err(e) => Some(e.to_string()),
+                };
+                delivery.last_status_code = result.as_ref().ok().map(|s| s.as_u16());
+                delivery.last_error = error.clone();
+                match error {
+                    None => {
+                        delivery.status = DeliveryStatus::Delivered;
+                        delivery.delivered_at = Some(now);
+                        delivery.next_attempt_at = None;
+                    }
+                    Some(e) => {
+                        warn!(
+                            "Webhook delivery {} to {} failed: {e}",
+                            delivery.id, delivery.webhook_id
+                        );
+                        if delivery.attempts >= *MAX_ATTEMPTS {
+                            delivery.status = DeliveryStatus::Failed;
+                            delivery.next_attempt_at = None;
+                        } else {
+                            delivery.next_attempt_at =
+                                chrono::Duration::from_std(retry_delay(delivery.attempts))
+                                    .ok()
+                                    .map(|delay| now + delay);
+                        }
+                    }
+                }
+            }
+            outbox.trim();
+            outbox.save();
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::test_fixtures::{infringement, pilot};
+
+    #[test]
+    fn delivery_bodies_are_redacted_without_read_pii() {
+        let at = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let infringement = Infringement {
+            pilot: Some(pilot("P-webhook-test")),
+            ..infringement("SN-webhook-test", at)
+        };
+        let mut redacted = infringement.clone();
+        redact_infringement(&mut redacted);
+        let webhook = |include_pii| {
+            Webhook::new(
+                "wh-test".to_string(),
+                false,
+                NewWebhook {
+                    url: "https://example.com/birdnest".to_string(),
+                    secret: "0123456789abcdef".to_string(),
+                    events: vec![],
+                    include_authorized: false,
+                    include_pii,
+                    template: None,
+                    note: None,
+                },
+            )
+        };
+        let delivery = |include_pii| {
+            new_delivery(
+                1,
+                &webhook(include_pii),
+                WebhookEvent::InfringementCreated,
+                at,
+                |pii, payload| Payload {
+                    infringement: Some(match pii {
+                        true => &infringement,
+                        false => &redacted,
+                    }),
+                    ..payload
+                },
+            )
+        };
+
+        let mut outbox = Outbox::default();
+        let (with_pii, redacted_body) = delivery(true);
+        outbox
+            .redacted_bodies
+            .insert(with_pii.id, redacted_body.unwrap());
+        assert!(with_pii.body.contains("test@example.com"));
+        let listed = outbox.for_caller(&with_pii, true);
+        assert!(listed.body.contains("test@example.com"));
+        let body = outbox.for_caller(&with_pii, false).body;
+        assert!(!body.contains("test@example.com"));
+        assert!(body.contains("SN-webhook-test"));
+
+        let (without_pii, redacted_body) = delivery(false);
+        assert_eq!(redacted_body, None);
+        assert_eq!(without_pii.body, body);
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use paperclip::actix::Apiv2Schema;
+use serde::Deserialize;
+
+use crate::{
+    config::Tier, features::review::ReviewState, reaktor::drones::Drone, Infringement,
+    InfringementStatus,
+};
+
+/// Orders for listing infringements
+#[derive(Deserialize, Apiv2Schema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InfringementSort {
+    /// Most severe first
+    Severity,
+    /// Closest first
+    Distance,
+    /// Most recently updated first
+    UpdatedAt,
+}
+
+/// Filters shared by every endpoint and command that lists infringements
+#[derive(Deserialize, Apiv2Schema, Debug, Clone, Default)]
+pub struct InfringementFilter {
+    /// An optional RFC3339 time stamp,
+    /// filters out infringements that have not been updated since min_updated_at.
+    /// In javascript you can use date.toISOString();
+    #[openapi(example = "2023-01-06T13:45:40.503Z")]
+    pub min_updated_at: Option<String>,
+    /// An optional RFC3339 time stamp,
+    /// filters out infringements that have been updated after max_updated_at.
+    #[openapi(example = "2023-01-06T13:55:40.503Z")]
+    pub max_updated_at: Option<String>,
+    /// infringing, authorized or approaching, all of them if left out
+    pub status: Option<InfringementStatus>,
+    /// open, acknowledged, dismissed or escalated
+    pub review_state: Option<ReviewState>,
+    /// Only infringements that have reached at least this tier
+    pub min_tier: Option<Tier>,
+    /// Only infringements in the zone with this id
+    #[openapi(example = "ndz")]
+    pub zone: Option<String>,
+    /// severity, distance or updated_at, keeps the cache order if left out
+    pub sort: Option<InfringementSort>,
+}
+
+impl InfringementFilter {
+    /// Keep only the infringements matching every filter and sort them,
+    /// fails if one of the filters can not be parsed
+    pub fn apply(&self, infringements: &mut Vec<Infringement>) -> Result<()> {
+        let matches = self.matcher()?;
+        infringements.retain(|i| matches(i));
+        self.sort(infringements);
+        Ok(())
+    }
+
+    /// Whether an infringement matches every filter, without sorting,
+    /// for going through infringements one at a time
+    pub fn matcher(&self) -> Result<impl Fn(&Infringement) -> bool + 'static> {
+        let min_updated_at = parse_time(&self.min_updated_at)?;
+        let max_updated_at = parse_time(&self.max_updated_at)?;
+        let (min_tier, status, review_state) = (self.min_tier, self.status, self.review_state);
+        let zone = self.zone.clone();
+        Ok(move |i: &Infringement| {
+            min_updated_at.is_none_or(|min| i.updated_at > min)
+                && max_updated_at.is_none_or(|max| i.updated_at <= max)
+                && min_tier.is_none_or(|tier| i.tier >= tier)
+                && zone.as_ref().is_none_or(|zone| i.zone == *zone)
+                && status.is_none_or(|status| i.status == status)
+                && review_state.is_none_or(|state| {
+                    i.review.as_ref().map(|r| r.state).unwrap_or_default() == state
+                })
+        })
+    }
+
+    /// Sort by the chosen order, keeps the order if there is none
+    pub fn sort(&self, infringements: &mut [Infringement]) {
+        match self.sort {
+            Some(InfringementSort::Severity) => {
+                infringements.sort_by(|a, b| b.severity.total_cmp(&a.severity))
+            }
+            Some(InfringementSort::Distance) => {
+                infringements.sort_by(|a, b| a.distance.total_cmp(&b.distance))
+            }
+            Some(InfringementSort::UpdatedAt) => {
+                infringements.sort_by_key(|i| std::cmp::Reverse(i.updated_at))
+            }
+            None => {}
+        }
+    }
+}
+
+fn parse_time(time: &Option<String>) -> Result<Option<DateTime<Utc>>> {
+    time.as_deref()
+        .map(|t| {
+            DateTime::parse_from_rfc3339(t)
+                .map(|t| t.with_timezone(&Utc))
+                .with_context(|| format!("Invalid time stamp \"{t}\""))
+        })
+        .transpose()
+}
+
+/// Filters for the drones in a snapshot, every filter that is set has to match
+#[derive(Deserialize, Apiv2Schema, Debug, Clone, Default)]
+pub struct DroneFilter {
+    /// Bounding box, smallest x-coordinate
+    pub min_x: Option<f64>,
+    /// Bounding box, smallest y-coordinate
+    pub min_y: Option<f64>,
+    /// Bounding box, largest x-coordinate
+    pub max_x: Option<f64>,
+    /// Bounding box, largest y-coordinate
+    pub max_y: Option<f64>,
+    /// Only drones within radius of (near_x, near_y)
+    pub near_x: Option<f64>,
+    pub near_y: Option<f64>,
+    pub radius: Option<f64>,
+    /// Case-insensitive manufacturer name
+    #[openapi(example = "MegaBuzzer Corp")]
+    pub manufacturer: Option<String>,
+    /// Case-insensitive model name
+    #[openapi(example = "Falcon")]
+    pub model: Option<String>,
+    pub min_altitude: Option<f64>,
+    pub max_altitude: Option<f64>,
+}
+
+impl DroneFilter {
+    /// Keep only the drones matching every filter,
+    /// fails if the radius filter is only partially given
+    pub fn apply(&self, drones: &mut Vec<Drone>) -> Result<()> {
+        let near = match (self.near_x, self.near_y, self.radius) {
+            (Some(x), Some(y), Some(radius)) => Some((x, y, radius)),
+            (None, None, None) => None,
+            _ => bail!("near_x, near_y and radius have to be given together"),
+        };
+        let is_within = |value: f64, min: Option<f64>, max: Option<f64>| {
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        };
+        let is_named = |value: &str, name: &Option<String>| {
+            name.as_ref()
+                .is_none_or(|name| value.eq_ignore_ascii_case(name))
+        };
+        drones.retain(|d| {
+            is_within(d.position_x, self.min_x, self.max_x)
+                && is_within(d.position_y, self.min_y, self.max_y)
+                && is_within(d.altitude, self.min_altitude, self.max_altitude)
+                && is_named(&d.manufacturer, &self.manufacturer)
+                && is_named(&d.model, &self.model)
+                && near.is_none_or(|(x, y, radius)| {
+                    (d.position_x - x).hypot(d.position_y - y) <= radius
+                })
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::test_fixtures::infringement;
+
+    fn listed(filter: &InfringementFilter) -> Vec<String> {
+        let at = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let mut infringements = vec![
+            Infringement {
+                tier: Tier::Critical,
+                severity: 40.0,
+                ..infringement("SN-critical", at)
+            },
+            Infringement {
+                severity: 70.0,
+                zone: "nest".to_string(),
+                ..infringement("SN-nest", at)
+            },
+            Infringement {
+                status: InfringementStatus::Approaching,
+                tier: Tier::Advisory,
+                severity: 10.0,
+                ..infringement("SN-approaching", at)
+            },
+        ];
+        filter.apply(&mut infringements).unwrap();
+        infringements
+            .into_iter()
+            .map(|i| i.drone_serial_number)
+            .collect()
+    }
+
+    #[test]
+    fn min_tier_keeps_worse_tiers() {
+        let filter = |tier| InfringementFilter {
+            min_tier: Some(tier),
+            ..Default::default()
+        };
+        assert_eq!(listed(&filter(Tier::Advisory)).len(), 3);
+        assert_eq!(listed(&filter(Tier::Warning)), ["SN-critical", "SN-nest"]);
+        assert_eq!(listed(&filter(Tier::Critical)), ["SN-critical"]);
+    }
+
+    #[test]
+    fn zone_keeps_the_zone() {
+        let filter = |zone: &str| InfringementFilter {
+            zone: Some(zone.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(listed(&filter("nest")), ["SN-nest"]);
+        assert_eq!(listed(&filter("ndz")), ["SN-critical", "SN-approaching"]);
+        assert!(listed(&filter("unknown")).is_empty());
+    }
+
+    #[test]
+    fn severity_sorts_the_most_severe_first() {
+        let filter = InfringementFilter {
+            sort: Some(InfringementSort::Severity),
+            ..Default::default()
+        };
+        assert_eq!(
+            listed(&filter),
+            ["SN-nest", "SN-critical", "SN-approaching"]
+        );
+    }
+}
@@ -1,13 +1,24 @@
 pub mod cache;
+pub mod changes;
+pub mod clock;
 pub mod config;
+pub mod events;
+pub mod filter;
 pub mod reaktor;
+pub mod schedule;
 pub mod server;
+pub mod storage;
+#[cfg(test)]
+mod test_fixtures;
 // optional features
 pub mod features;
 
 pub mod prelude {
     pub use crate::cache;
+    pub use crate::changes;
+    pub use crate::clock;
     pub use crate::config;
+    pub use crate::events;
     pub use crate::get_infringements;
     pub use crate::reaktor;
     pub use crate::record_infringements;
@@ -15,93 +26,497 @@ pub mod prelude {
 }
 
 use anyhow::Result;
-use config::{get_drone_distance_to_ndz, NDZ_MIN_ALLOWED_DISTANCE};
+use chrono::{DateTime, Utc};
+use config::{
+    active_zones, zones, Tier, Zone, NDZ_MIN_ALLOWED_DISTANCE, SEVERITY_ALTITUDE_WEIGHT,
+    SEVERITY_DWELL_WEIGHT, SEVERITY_MAX_ALTITUDE, SEVERITY_MAX_DWELL_SECS,
+    SEVERITY_PROXIMITY_WEIGHT,
+};
 use futures::future;
 use log::debug;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use reaktor::{drones::Drone, pilots::Pilot};
+use reaktor::{
+    drones::{Drone, DronesDocument},
+    pilots::Pilot,
+};
 use serde::{Deserialize, Serialize};
 
-use cache::INFRINGEMENTS;
+use cache::{INFRINGEMENTS, INFRINGEMENTS_REVISION};
+use changes::CHANGES;
+use clock::Clock;
+use events::EVENTS;
+use features::{
+    alerts, email,
+    exemptions::find_exemption,
+    review::Review,
+    webhooks::{self, WebhookEvent},
+};
 
 /// Get infringements and save them to [INFRINGEMENTS]
-pub async fn record_infringements() -> Result<()> {
-    let infringements = get_infringements().await?;
+pub async fn record_infringements(clock: &dyn Clock) -> Result<()> {
+    let doc = reaktor::drones::get_drones(clock).await?;
+    let seen_at = doc.snapshot_time().unwrap_or_else(|| clock.now());
+    EVENTS.lock().unwrap().observe(&doc, seen_at);
+    let infringements = infringements_in(doc, clock).await;
+    let infringements_empty = infringements.is_empty();
     let cache = INFRINGEMENTS.lock().await;
+    let mut touched = vec![];
+    let mut created = vec![];
     for i in infringements {
         let key = i.drone_serial_number.clone();
-        match cache.get(&key) {
-            Some(existing) => {
-                let new = Infringement {
-                    drone_serial_number: existing.drone_serial_number,
-                    pilot: i.pilot,
-                    updated_at: i.updated_at,
-                    distance: existing.distance.min(i.distance),
-                    x: if i.distance > existing.distance {
-                        existing.x
-                    } else {
-                        i.x
-                    },
-                    y: if i.distance > existing.distance {
-                        existing.y
-                    } else {
-                        i.y
-                    },
-                };
-                cache.insert(key, new)
+        let (new, events) = match cache.get(&key) {
+            Some(existing) => merge(&existing, i),
+            None if i.status == InfringementStatus::Approaching => (i, vec![]),
+            None => (i, vec![(WebhookEvent::InfringementCreated, None)]),
+        };
+        cache.insert(key, new.clone()).await;
+        CHANGES.lock().unwrap().upsert(&new);
+        for (event, previous_tier) in events {
+            webhooks::notify(event, &new, previous_tier);
+            email::notify(event, &new, previous_tier);
+            // Both start an offense
+            if matches!(
+                event,
+                WebhookEvent::InfringementCreated | WebhookEvent::InfringementStatusChanged
+            ) {
+                created.push(new.clone());
             }
-            None => cache.insert(i.drone_serial_number.clone(), i),
         }
-        .await;
+        touched.push(new);
+    }
+    if !infringements_empty {
+        INFRINGEMENTS_REVISION.bump();
     }
     debug!(
         "{} infringements in the last 10 minutes",
         cache.entry_count()
     );
+    let active: Vec<_> = cache.iter().map(|(_, i)| i).collect();
+    drop(cache);
+    // Pilot rules read the history from disk
+    tokio::task::spawn_blocking(move || alerts::evaluate(&touched, &created, &active, seen_at))
+        .await?;
 
     Ok(())
 }
 
-pub async fn get_infringements() -> Result<Vec<Infringement>> {
-    let doc = reaktor::drones::get_drones().await?;
+/// Merge a new sighting into the infringement of the same visit,
+/// with the events the change causes
+fn merge(
+    existing: &Infringement,
+    i: Infringement,
+) -> (Infringement, Vec<(WebhookEvent, Option<Tier>)>) {
+    // Being inside a zone wins, then the worst tier, the distance only decides
+    // between equal tiers
+    let inside = |i: &Infringement| i.status != InfringementStatus::Approaching;
+    let closer =
+        (inside(&i), i.tier, -i.distance) > (inside(existing), existing.tier, -existing.distance);
+    // The same snapshot can be recorded more than once
+    let new_snapshot = i.last_seen_at > existing.last_seen_at;
+    let closest = if closer { &i } else { existing };
+    // Infringing wins: a visit that was infringing at any point stays infringing,
+    // even if an exemption is granted later. An authorized visit turns infringing
+    // once its exemption expires or the drone enters a zone it is not exempt in,
+    // and stays authorized while the drone is only approaching
+    let (status, exemption_id) = match (existing.status, i.status) {
+        (InfringementStatus::Infringing, _) => (InfringementStatus::Infringing, None),
+        (InfringementStatus::Authorized, InfringementStatus::Approaching) => {
+            (existing.status, existing.exemption_id)
+        }
+        _ => (i.status, i.exemption_id),
+    };
+    let mut merged = Infringement {
+        drone_serial_number: existing.drone_serial_number.clone(),
+        pilot: i.pilot.clone(),
+        status,
+        exemption_id,
+        review: None,
+        zone: closest.zone.clone(),
+        tier: closest.tier,
+        severity: 0.0,
+        distance: closest.distance,
+        x: closest.x,
+        y: closest.y,
+        closest_approach_at: closest.closest_approach_at,
+        closest_approach_altitude: closest.closest_approach_altitude,
+        first_seen_at: existing.first_seen_at.min(i.first_seen_at),
+        last_seen_at: existing.last_seen_at.max(i.last_seen_at),
+        snapshots_inside: existing.snapshots_inside + u32::from(new_snapshot),
+        updated_at: i.updated_at,
+    };
+    merged.severity = merged.severity_score();
+    let mut events = vec![];
+    if existing.status == InfringementStatus::Approaching {
+        // The infringement starts once the drone enters a zone
+        if merged.status != InfringementStatus::Approaching {
+            events.push((WebhookEvent::InfringementCreated, None));
+        }
+        return (merged, events);
+    }
+    if merged.status != existing.status {
+        events.push((WebhookEvent::InfringementStatusChanged, None));
+    }
+    if merged.tier != existing.tier {
+        events.push((WebhookEvent::InfringementTierChanged, Some(existing.tier)));
+    }
+    (merged, events)
+}
+
+pub async fn get_infringements(clock: &dyn Clock) -> Result<Vec<Infringement>> {
+    let doc = reaktor::drones::get_drones(clock).await?;
+    Ok(infringements_in(doc, clock).await)
+}
+
+/// The drones of a snapshot that are inside a ring of any zone, with their pilots.
+/// Drones that are only inside the outer rings of zones are approaching
+pub async fn infringements_in(doc: DronesDocument, clock: &dyn Clock) -> Vec<Infringement> {
+    let updated_at = clock.now();
+    // Fall back to the clock if the sensor sent an unreadable timestamp
+    let seen_at = doc.snapshot_time().unwrap_or(updated_at);
     let drones = doc.capture.drone;
+    let zones = active_zones(seen_at);
     let tasks: Vec<_> = drones
         .par_iter()
-        .map(|drone| DroneWithDistance {
-            drone: drone.clone(),
-            distance: get_drone_distance_to_ndz(drone),
+        .map(|drone| {
+            // Every zone the drone is inside a ring of
+            zones
+                .iter()
+                .filter_map(|zone| {
+                    let distance = zone.distance_to(drone);
+                    let tier = zone.tier_at(distance)?;
+                    Some(DroneWithDistance {
+                        drone: drone.clone(),
+                        distance,
+                        zone: zone.id.clone(),
+                        tier,
+                        inside: zone.contains(distance),
+                    })
+                })
+                .collect::<Vec<_>>()
         })
-        .filter(|data| data.distance < NDZ_MIN_ALLOWED_DISTANCE)
-        .map(|data| async move {
-            let pilot = reaktor::pilots::get_pilot(&data.drone.serial_number)
+        .filter(|inside| !inside.is_empty())
+        .map(|inside| async move {
+            let pilot = reaktor::pilots::get_pilot(&inside[0].drone.serial_number)
                 .await
                 .ok();
+            // The drone is only authorized if it is exempt in every zone it is in,
+            // and only approaching if it is not inside any zone.
+            // The worst zone it is not exempt in is recorded, otherwise the worst zone.
+            // Zones the drone is inside of come first, the closest zone wins between
+            // equal tiers
+            let worst = |a: &(DroneWithDistance, Option<u64>),
+                         b: &(DroneWithDistance, Option<u64>)| {
+                (a.0.inside, a.0.tier)
+                    .cmp(&(b.0.inside, b.0.tier))
+                    .then(b.0.distance.total_cmp(&a.0.distance))
+            };
+            let inside: Vec<_> = inside
+                .into_iter()
+                .map(|data| {
+                    let exemption = data
+                        .inside
+                        .then(|| find_exemption(&data.drone, pilot.as_ref(), &data.zone, seen_at))
+                        .flatten();
+                    (data, exemption)
+                })
+                .collect();
+            let unexempt = inside
+                .iter()
+                .filter(|(data, exemption)| data.inside && exemption.is_none())
+                .max_by(|a, b| worst(a, b));
+            let (data, exemption_id) = unexempt
+                .or_else(|| inside.iter().max_by(|a, b| worst(a, b)))
+                .cloned()
+                .expect("The drone is inside at least one ring");
             let drone_serial_number = data.drone.serial_number;
-            Infringement {
+            let mut infringement = Infringement {
                 drone_serial_number,
                 pilot,
+                status: match exemption_id {
+                    _ if !data.inside => InfringementStatus::Approaching,
+                    Some(_) => InfringementStatus::Authorized,
+                    None => InfringementStatus::Infringing,
+                },
+                exemption_id,
+                review: None,
+                zone: data.zone,
+                tier: data.tier,
+                severity: 0.0,
                 distance: data.distance,
                 x: data.drone.position_x,
                 y: data.drone.position_y,
-                updated_at: chrono::offset::Utc::now().to_rfc3339(),
-            }
+                closest_approach_at: seen_at,
+                closest_approach_altitude: data.drone.altitude,
+                first_seen_at: seen_at,
+                last_seen_at: seen_at,
+                snapshots_inside: 1,
+                updated_at,
+            };
+            infringement.severity = infringement.severity_score();
+            infringement
         })
         .collect();
 
-    Ok(future::join_all(tasks).await)
+    future::join_all(tasks).await
 }
 
 #[derive(Debug, Clone)]
 pub struct DroneWithDistance {
     pub drone: Drone,
     pub distance: f64,
+    pub zone: String,
+    pub tier: Tier,
+    /// Inside the zone, not only one of its outer rings
+    pub inside: bool,
 }
 #[derive(Debug, Clone, Serialize, Deserialize, paperclip::actix::Apiv2Schema)]
 pub struct Infringement {
     pub drone_serial_number: String,
     pub pilot: Option<Pilot>,
+    /// Authorized if the drone or pilot has an exemption for the zone
+    pub status: InfringementStatus,
+    /// The exemption that authorized the drone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exemption_id: Option<u64>,
+    /// What operators have done about the infringement, missing until someone reviews it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub review: Option<Review>,
+    /// The zone the worst tier was reached in
+    pub zone: String,
+    /// The worst tier reached
+    pub tier: Tier,
+    /// 0 to 100, combines the distance, dwell time and altitude
+    pub severity: f64,
+    /// The closest distance to the zone center
     pub distance: f64,
+    /// Position at the closest approach
     pub x: f64,
     pub y: f64,
-    pub updated_at: String,
+    /// Snapshot time of the closest approach
+    pub closest_approach_at: DateTime<Utc>,
+    /// Altitude at the closest approach
+    pub closest_approach_altitude: f64,
+    /// Snapshot time the drone was first seen inside a zone
+    pub first_seen_at: DateTime<Utc>,
+    /// Snapshot time the drone was last seen inside a zone
+    pub last_seen_at: DateTime<Utc>,
+    /// In how many snapshots the drone was inside a zone
+    pub snapshots_inside: u32,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, paperclip::actix::Apiv2Schema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum InfringementStatus {
+    #[default]
+    Infringing,
+    /// The drone or its pilot has a valid exemption
+    Authorized,
+    /// The drone only entered a ring outside the zones, it is not an infringement
+    Approaching,
+}
+
+impl InfringementStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InfringementStatus::Infringing => "infringing",
+            InfringementStatus::Authorized => "authorized",
+            InfringementStatus::Approaching => "approaching",
+        }
+    }
+}
+
+impl Infringement {
+    /// Closer, longer and lower infringements score higher
+    pub fn severity_score(&self) -> f64 {
+        let outer_radius = zones()
+            .iter()
+            .find(|zone| zone.id == self.zone)
+            .map_or(NDZ_MIN_ALLOWED_DISTANCE, Zone::outer_radius);
+        let proximity = (1.0 - self.distance / outer_radius).clamp(0.0, 1.0);
+        let dwell_secs =
+            (self.last_seen_at - self.first_seen_at).num_milliseconds() as f64 / 1000.0;
+        let dwell = (dwell_secs / SEVERITY_MAX_DWELL_SECS).clamp(0.0, 1.0);
+        let low_altitude =
+            (1.0 - self.closest_approach_altitude / SEVERITY_MAX_ALTITUDE).clamp(0.0, 1.0);
+        SEVERITY_PROXIMITY_WEIGHT * proximity
+            + SEVERITY_DWELL_WEIGHT * dwell
+            + SEVERITY_ALTITUDE_WEIGHT * low_altitude
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, TimeZone};
+
+    use super::*;
+    use crate::cache::PILOT_CACHE;
+    use crate::clock::ManualClock;
+    use crate::test_fixtures::{infringement, pilot, snapshot};
+
+    #[tokio::test]
+    async fn infringements_use_the_clock_and_snapshot_time() {
+        let serial = "SN-clock-test".to_string();
+        PILOT_CACHE
+            .lock()
+            .await
+            .insert(serial.clone(), pilot("P-clock-test"))
+            .await;
+        let start = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let clock = ManualClock::new(start);
+
+        // The clock stands in for timestamps the sensor got wrong
+        let found = infringements_in(snapshot("not a timestamp", &[(&serial, 0.0)]), &clock).await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].first_seen_at, start);
+        assert_eq!(found[0].updated_at, start);
+        assert_eq!(found[0].tier, Tier::Critical);
+
+        clock.advance(Duration::minutes(5));
+        let found =
+            infringements_in(snapshot("2023-06-28T12:01:00Z", &[(&serial, 0.0)]), &clock).await;
+        assert_eq!(
+            found[0].first_seen_at,
+            Utc.with_ymd_and_hms(2023, 6, 28, 12, 1, 0).unwrap()
+        );
+        assert_eq!(found[0].updated_at, start + Duration::minutes(5));
+    }
+
+    #[tokio::test]
+    async fn drones_in_the_advisory_ring_are_approaching() {
+        let serial = "SN-approaching-test".to_string();
+        PILOT_CACHE
+            .lock()
+            .await
+            .insert(serial.clone(), pilot("P-approaching-test"))
+            .await;
+        let clock = ManualClock::new(Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap());
+
+        let found = infringements_in(
+            snapshot("2023-06-28T12:00:00Z", &[(&serial, 120_000.0)]),
+            &clock,
+        )
+        .await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].status, InfringementStatus::Approaching);
+        assert_eq!(found[0].tier, Tier::Advisory);
+        assert_eq!(found[0].exemption_id, None);
+
+        let found = infringements_in(
+            snapshot("2023-06-28T12:00:00Z", &[(&serial, 160_000.0)]),
+            &clock,
+        )
+        .await;
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn severity_combines_distance_dwell_and_altitude() {
+        let at = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        // 60 m of the 150 m advisory ring, no dwell time and 4 m up
+        let i = infringement("SN-severity-test", at);
+        assert!((i.severity_score() - (36.0 + 9.0)).abs() < 1e-9);
+
+        let worst = Infringement {
+            distance: 0.0,
+            closest_approach_altitude: 0.0,
+            last_seen_at: at + Duration::seconds(SEVERITY_MAX_DWELL_SECS as i64 * 2),
+            ..i.clone()
+        };
+        assert_eq!(worst.severity_score(), 100.0);
+
+        let least = Infringement {
+            distance: 200_000.0,
+            closest_approach_altitude: SEVERITY_MAX_ALTITUDE * 2.0,
+            ..i.clone()
+        };
+        assert_eq!(least.severity_score(), 0.0);
+
+        // Zones that are gone fall back to the radius of the no-drone-zone
+        let unknown = Infringement {
+            zone: "gone".to_string(),
+            ..i
+        };
+        assert!((unknown.severity_score() - (24.0 + 9.0)).abs() < 1e-9);
+    }
+
+    fn sighting(status: InfringementStatus, tier: Tier, at: DateTime<Utc>) -> Infringement {
+        Infringement {
+            status,
+            exemption_id: (status == InfringementStatus::Authorized).then_some(1),
+            tier,
+            ..infringement("SN-merge-test", at)
+        }
+    }
+
+    #[test]
+    fn infringing_wins_when_merging() {
+        use InfringementStatus::{Authorized, Infringing};
+        let start = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let later = start + Duration::seconds(2);
+
+        // The exemption expired during the visit
+        let (merged, events) = merge(
+            &sighting(Authorized, Tier::Warning, start),
+            sighting(Infringing, Tier::Warning, later),
+        );
+        assert_eq!(merged.status, Infringing);
+        assert_eq!(merged.exemption_id, None);
+        assert_eq!(merged.snapshots_inside, 2);
+        assert_eq!(events, [(WebhookEvent::InfringementStatusChanged, None)]);
+
+        // An exemption granted during the visit does not excuse it
+        let (merged, events) = merge(
+            &sighting(Infringing, Tier::Warning, start),
+            sighting(Authorized, Tier::Critical, later),
+        );
+        assert_eq!(merged.status, Infringing);
+        assert_eq!(merged.exemption_id, None);
+        assert_eq!(
+            events,
+            [(WebhookEvent::InfringementTierChanged, Some(Tier::Warning))]
+        );
+
+        let (merged, events) = merge(
+            &sighting(Authorized, Tier::Warning, start),
+            sighting(Authorized, Tier::Warning, later),
+        );
+        assert_eq!(merged.status, Authorized);
+        assert_eq!(merged.exemption_id, Some(1));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn approaching_visits_become_infringements_inside_a_zone() {
+        use InfringementStatus::{Approaching, Authorized, Infringing};
+        let start = Utc.with_ymd_and_hms(2023, 6, 28, 12, 0, 0).unwrap();
+        let later = start + Duration::seconds(2);
+
+        let (merged, events) = merge(
+            &sighting(Approaching, Tier::Advisory, start),
+            sighting(Infringing, Tier::Warning, later),
+        );
+        assert_eq!(merged.status, Infringing);
+        assert_eq!(merged.tier, Tier::Warning);
+        assert_eq!(events, [(WebhookEvent::InfringementCreated, None)]);
+
+        let (merged, events) = merge(
+            &sighting(Approaching, Tier::Advisory, start),
+            sighting(Approaching, Tier::Advisory, later),
+        );
+        assert_eq!(merged.status, Approaching);
+        assert!(events.is_empty());
+
+        // Leaving the zone does not end the exemption
+        let (merged, events) = merge(
+            &sighting(Authorized, Tier::Warning, start),
+            sighting(Approaching, Tier::Advisory, later),
+        );
+        assert_eq!(merged.status, Authorized);
+        assert_eq!(merged.exemption_id, Some(1));
+        assert_eq!(merged.tier, Tier::Warning);
+        assert!(events.is_empty());
+    }
 }
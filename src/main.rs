@@ -1,37 +1,92 @@
-use log::info;
+use log::{error, info};
 use std::time::Duration;
 
 // Import core functionality from lib.rs
-use birdnest_api::prelude::{record_infringements, server};
+use birdnest_api::features::{
+    alerts, email, exemptions, export, privacy,
+    replay::{self, session},
+    review, webhooks,
+};
+use birdnest_api::prelude::{clock, config, record_infringements, server};
+use birdnest_api::server::auth::hash_key;
 
 // Tokio is used as the async runtime
 #[tokio::main]
 async fn main() {
+    // Manage recorded sessions without starting the server
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(|a| a.as_str()) {
+        Some("sessions") => Some(session::run_cli(&args[2..])),
+        // Export archived infringements without starting the server
+        Some("export") => Some(export::run_cli(&args[2..])),
+        // Print the hash of an api key for the key file
+        Some("hash-key") => Some(match args.get(2) {
+            Some(key) => {
+                println!("{}", hash_key(key));
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Usage: birdnest-api hash-key <key>")),
+        }),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
     println!("Starting the Birdnest API server");
     println!("Tip: use --record to record data and --replay to replay the recorded data");
+    println!("Tip: use --session <id> to name the recorded session or choose the one to replay");
+    println!("Tip: use --replay-end <loop|loop-clear|hold|shutdown> to choose what happens when a replay ends");
     // Enable fancier logging
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
     env_logger::init();
+    // Stop on invalid options before anything runs with them
+    if let Err(e) = replay::init()
+        .and_then(|_| config::init())
+        .and_then(|_| alerts::init())
+        .and_then(|_| email::init())
+        .and_then(|_| exemptions::init())
+        .and_then(|_| privacy::init())
+    {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    }
+    // Either wall-clock time or the recorded time of the replay
+    let clock = clock::default_clock();
+    let server_clock = clock.clone();
     // Fetch infringements in the background
-    let background_task = tokio::spawn(async {
+    let background_task = tokio::spawn(async move {
         info!("Background task started!");
         loop {
-            tokio::spawn(async {
-                record_infringements()
-                    .await
-                    .expect("Failed to update infringements");
+            let clock = clock.clone();
+            tokio::spawn(async move {
+                if let Err(e) = record_infringements(clock.as_ref()).await {
+                    error!("Failed to update infringements: {e:#}");
+                }
             });
             tokio::time::sleep(Duration::from_secs(2)).await;
         }
     });
+    // Send webhooks from the outbox
+    let webhook_task = tokio::spawn(webhooks::run_deliveries());
+    // Send queued emails, if an SMTP server is configured
+    let email_task = tokio::spawn(email::run_email());
+    // Save reviews of evicted infringements and forget old ones
+    let review_task = tokio::spawn(review::run_maintenance());
     // Start the api
-    server::start()
+    server::start(server_clock)
         .await
         .expect("Failed to start the api server");
     // Continues once the server has stopped
     info!("The server has stopped, stopping the background task...");
     background_task.abort();
+    webhook_task.abort();
+    email_task.abort();
+    review_task.abort();
     info!("Everything done, bye!")
 }
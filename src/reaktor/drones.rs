@@ -1,30 +1,35 @@
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
-use crate::features::replay::{get_replay_status, load_replay_drones, ReplayStatus};
+use crate::clock::Clock;
+use crate::features::replay::{
+    get_replay_status, load_replay_drones, next_replay_step, ReplayStatus,
+};
 
 use super::DRONES_ENDPOINT;
 
-pub async fn get_drones() -> Result<DronesDocument> {
+pub async fn get_drones(clock: &dyn Clock) -> Result<DronesDocument> {
     if get_replay_status() == ReplayStatus::Replaying {
-        let history = load_replay_drones().unwrap();
-        let unix_now = chrono::Utc::now().timestamp();
+        let history = load_replay_drones()?;
         let history_len = history.len();
         if history_len == 0 {
             return Err(anyhow!("No drones in replay history"));
         }
-        // We'll just assume this for now
-        let seconds_between_updates = 2;
-        // We use this to get a deterministic index that is continous over time
-        let index = ((unix_now / seconds_between_updates) % history_len as i64) as usize;
+        // Every replay starts from the first snapshot and advances one snapshot per update
+        let step = next_replay_step(history_len);
+        let index = step.index;
         info!("Replaying drones from index {} / {}", index, history_len);
-        if index == 0 {
+        if step.clear {
             warn!("Replaying drones from the beginning, invalidating all previous infringements");
             let infringements = crate::INFRINGEMENTS.lock().await;
             infringements.invalidate_all();
+            crate::cache::INFRINGEMENTS_REVISION.bump();
         }
-        let doc = history[index].clone();
+        let mut doc = history[index].clone();
+        doc.replay_run = Some(step.run);
+        doc.observe_snapshot_time(clock);
         *crate::cache::LATEST_DRONE_SNAPSHOT.lock().await = Some(doc.clone());
         return Ok(doc);
     }
@@ -33,9 +38,10 @@ pub async fn get_drones() -> Result<DronesDocument> {
     if status.is_success() {
         let xml = response.text().await?;
         let doc: DronesDocument = quick_xml::de::from_str(&xml)?;
+        doc.observe_snapshot_time(clock);
 
         *crate::cache::LATEST_DRONE_SNAPSHOT.lock().await = Some(doc.clone());
-        crate::features::replay::save(chrono::Utc::now()).await;
+        crate::features::replay::save(clock.now()).await;
 
         Ok(doc)
     } else {
@@ -52,6 +58,33 @@ pub struct DronesDocument {
     #[serde(alias = "deviceInformation")]
     pub device_information: DronesSensorInfo,
     pub capture: DronesCapture,
+    /// Counts how many times a replay has started over, [None] for live snapshots.
+    /// Snapshot times only go backwards when this increases
+    #[serde(skip)]
+    pub replay_run: Option<u64>,
+}
+
+impl DronesDocument {
+    /// The point in time the sensor took this snapshot at
+    pub fn snapshot_time(&self) -> Option<DateTime<Utc>> {
+        match DateTime::parse_from_rfc3339(&self.capture.snapshot_timestamp) {
+            Ok(time) => Some(time.with_timezone(&Utc)),
+            Err(e) => {
+                warn!(
+                    "Failed to parse snapshot timestamp {}: {e}",
+                    self.capture.snapshot_timestamp
+                );
+                None
+            }
+        }
+    }
+
+    /// Lets the clock know which point in time this snapshot was taken at
+    fn observe_snapshot_time(&self, clock: &dyn Clock) {
+        if let Some(time) = self.snapshot_time() {
+            clock.observe_snapshot(time);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -76,7 +109,7 @@ pub struct DronesCapture {
     pub drone: Vec<Drone>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, paperclip::actix::Apiv2Schema)]
 pub struct Drone {
     #[serde(alias = "serialNumber")]
     pub serial_number: String,
@@ -49,17 +49,21 @@ pub async fn get_pilot(drone_serial_number: &String) -> Result<Pilot> {
     }
 }
 
+/// Contact details are redacted following BIRDNEST_REDACT unless the caller has the read:pii scope
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, paperclip::actix::Apiv2Schema)]
 pub struct Pilot {
     #[serde(alias = "pilotId")]
     pub pilot_id: String,
     #[serde(alias = "firstName")]
     pub first_name: String,
+    /// Only the initial unless the caller has the read:pii scope
     #[serde(alias = "lastName")]
     pub last_name: String,
+    /// [REDACTED] unless the caller has the read:pii scope
     #[serde(alias = "phoneNumber")]
     pub phone_number: String,
     #[serde(alias = "createdDt")]
     pub created_date: String,
+    /// Partially masked unless the caller has the read:pii scope
     pub email: String,
 }
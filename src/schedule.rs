@@ -0,0 +1,450 @@
+use anyhow::{bail, Context, Result};
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+
+/// How far ahead [Schedule::next_change] looks
+const LOOKAHEAD_DAYS: i64 = 400;
+
+/// When a zone is active. Every date range and window is in the schedule's time zone,
+/// a zone without a schedule is always active
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct Schedule {
+    /// An IANA time zone name
+    #[openapi(example = "Europe/Helsinki")]
+    pub time_zone: String,
+    /// Location of the zone, needed for sunrise and sunset times
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Days the zone is active on, every day if empty
+    #[serde(default)]
+    pub date_ranges: Vec<DateRange>,
+    /// Times of day the zone is active at, the whole day if empty
+    #[serde(default)]
+    pub windows: Vec<Window>,
+}
+
+/// An inclusive range of days, either "YYYY-MM-DD" or "MM-DD" for a range that repeats
+/// every year, such as the breeding season
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct DateRange {
+    #[openapi(example = "04-01")]
+    pub start: String,
+    #[openapi(example = "07-31")]
+    pub end: String,
+}
+
+/// A daily time window, windows ending before they start continue past midnight
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct Window {
+    /// Weekdays the window starts on, such as "mon" or "monday", every day if empty
+    #[serde(default)]
+    pub days: Vec<String>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+/// Either a clock time "HH:MM" or minutes relative to sunrise or sunset,
+/// such as `{ "sunset": -30 }` for half an hour before sunset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+#[serde(untagged)]
+pub enum TimeOfDay {
+    Clock(String),
+    Sunrise { sunrise: i64 },
+    Sunset { sunset: i64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DateBound {
+    Fixed(NaiveDate),
+    /// Month and day
+    Yearly(u32, u32),
+}
+
+impl DateBound {
+    fn parse(value: &str) -> Result<Self> {
+        if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+            return Ok(DateBound::Fixed(date));
+        }
+        let (month, day) = value
+            .split_once('-')
+            .and_then(|(m, d)| Some((m.parse().ok()?, d.parse().ok()?)))
+            .with_context(|| format!("Invalid date \"{value}\", expected YYYY-MM-DD or MM-DD"))?;
+        // 2024 is a leap year, so 02-29 is allowed
+        NaiveDate::from_ymd_opt(2024, month, day)
+            .with_context(|| format!("Invalid date \"{value}\""))?;
+        Ok(DateBound::Yearly(month, day))
+    }
+}
+
+impl DateRange {
+    fn contains(&self, date: NaiveDate) -> bool {
+        let (Ok(start), Ok(end)) = (DateBound::parse(&self.start), DateBound::parse(&self.end))
+        else {
+            return false;
+        };
+        match (start, end) {
+            (DateBound::Fixed(start), DateBound::Fixed(end)) => start <= date && date <= end,
+            (DateBound::Yearly(sm, sd), DateBound::Yearly(em, ed)) => {
+                let day = (date.month(), date.day());
+                if (sm, sd) <= (em, ed) {
+                    (sm, sd) <= day && day <= (em, ed)
+                } else {
+                    // Wraps over the new year
+                    (sm, sd) <= day || day <= (em, ed)
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Schedule {
+    /// Check that the schedule can be evaluated
+    pub fn validate(&self) -> Result<()> {
+        self.tz()?;
+        for range in &self.date_ranges {
+            let start = DateBound::parse(&range.start)?;
+            let end = DateBound::parse(&range.end)?;
+            if std::mem::discriminant(&start) != std::mem::discriminant(&end) {
+                bail!(
+                    "Date range {} - {} mixes yearly and fixed dates",
+                    range.start,
+                    range.end
+                );
+            }
+        }
+        for window in &self.windows {
+            for day in &window.days {
+                day.parse::<Weekday>()
+                    .map_err(|_| anyhow::anyhow!("Invalid weekday \"{day}\""))?;
+            }
+            for time in [&window.start, &window.end] {
+                match time {
+                    TimeOfDay::Clock(clock) => {
+                        parse_clock(clock)?;
+                    }
+                    _ if self.latitude.is_none() || self.longitude.is_none() => {
+                        bail!("Sunrise and sunset times need a latitude and longitude")
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn tz(&self) -> Result<Tz> {
+        self.time_zone
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid time zone \"{}\": {e}", self.time_zone))
+    }
+
+    fn is_active_on(&self, date: NaiveDate) -> bool {
+        self.date_ranges.is_empty() || self.date_ranges.iter().any(|r| r.contains(date))
+    }
+
+    /// The instant a time of day happens on a local date, None if the sun does not
+    /// rise or set that day
+    fn resolve(&self, tz: Tz, date: NaiveDate, time: &TimeOfDay) -> Option<DateTime<Utc>> {
+        let sun = |rising: bool, offset: i64| {
+            let event = sun_event(date, self.latitude?, self.longitude?, rising)?;
+            Some(event + Duration::minutes(offset))
+        };
+        match time {
+            TimeOfDay::Clock(clock) => {
+                Some(local_to_utc(tz, date.and_time(parse_clock(clock).ok()?)))
+            }
+            TimeOfDay::Sunrise { sunrise } => sun(true, *sunrise),
+            TimeOfDay::Sunset { sunset } => sun(false, *sunset),
+        }
+    }
+
+    /// The active interval of a window starting on a local date
+    fn window_on(
+        &self,
+        tz: Tz,
+        date: NaiveDate,
+        window: &Window,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let starts_today = window
+            .days
+            .iter()
+            .any(|day| day.parse::<Weekday>().ok() == Some(date.weekday()));
+        if !window.days.is_empty() && !starts_today {
+            return None;
+        }
+        let start = self.resolve(tz, date, &window.start)?;
+        let mut end = self.resolve(tz, date, &window.end)?;
+        if end <= start {
+            end = self
+                .resolve(tz, date.succ_opt()?, &window.end)
+                .unwrap_or(end + Duration::days(1));
+        }
+        Some((start, end))
+    }
+
+    pub fn is_active(&self, time: DateTime<Utc>) -> bool {
+        let Ok(tz) = self.tz() else {
+            return true;
+        };
+        let date = time.with_timezone(&tz).date_naive();
+        if self.windows.is_empty() {
+            return self.is_active_on(date);
+        }
+        // Windows from the previous day can continue past midnight
+        [date.pred_opt(), Some(date)]
+            .into_iter()
+            .flatten()
+            .filter(|day| self.is_active_on(*day))
+            .any(|day| {
+                self.windows.iter().any(|window| {
+                    self.window_on(tz, day, window)
+                        .is_some_and(|(start, end)| start <= time && time < end)
+                })
+            })
+    }
+
+    /// The first moment after `time` at which the zone becomes active or inactive
+    pub fn next_change(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let tz = self.tz().ok()?;
+        let today = time.with_timezone(&tz).date_naive();
+        let mut candidates = vec![];
+        for offset in -1..LOOKAHEAD_DAYS {
+            let day = today + Duration::days(offset);
+            candidates.push(local_to_utc(tz, day.and_time(NaiveTime::MIN)));
+            for window in &self.windows {
+                if let Some((start, end)) = self.window_on(tz, day, window) {
+                    candidates.extend([start, end]);
+                }
+            }
+        }
+        candidates.retain(|candidate| *candidate > time);
+        candidates.sort();
+        let active = self.is_active(time);
+        candidates
+            .into_iter()
+            .find(|candidate| self.is_active(*candidate) != active)
+    }
+}
+
+fn parse_clock(value: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .with_context(|| format!("Invalid time \"{value}\", expected HH:MM"))
+}
+
+/// Local times skipped by a daylight saving change are moved forward by an hour
+fn local_to_utc(tz: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&local))
+}
+
+/// Sunrise or sunset with the NOAA sunrise equation, None during polar day or night
+fn sun_event(
+    date: NaiveDate,
+    latitude: f64,
+    longitude: f64,
+    rising: bool,
+) -> Option<DateTime<Utc>> {
+    const J2000: f64 = 2451545.0;
+    const UNIX_EPOCH_JULIAN: f64 = 2440587.5;
+    let days_since_epoch = (date - NaiveDate::from_ymd_opt(1970, 1, 1)?).num_days() as f64;
+    let julian_day = (UNIX_EPOCH_JULIAN + days_since_epoch - J2000 + 0.0008).ceil();
+    let mean_solar_noon = julian_day - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = J2000 + mean_solar_noon + 0.0053 * anomaly.sin()
+        - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+    let julian = if rising {
+        transit - hour_angle
+    } else {
+        transit + hour_angle
+    };
+    let millis = ((julian - UNIX_EPOCH_JULIAN) * 86_400_000.0).round() as i64;
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn schedule(value: serde_json::Value) -> Schedule {
+        let schedule: Schedule = serde_json::from_value(value).unwrap();
+        schedule.validate().unwrap();
+        schedule
+    }
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn windows_continue_past_midnight() {
+        let schedule = schedule(json!({
+            "time_zone": "UTC",
+            "windows": [{ "days": ["fri"], "start": "22:00", "end": "02:00" }]
+        }));
+        // 2023-06-30 is a Friday
+        assert!(!schedule.is_active(utc("2023-06-30T21:59:00Z")));
+        assert!(schedule.is_active(utc("2023-06-30T22:00:00Z")));
+        assert!(schedule.is_active(utc("2023-07-01T01:59:00Z")));
+        assert!(!schedule.is_active(utc("2023-07-01T02:00:00Z")));
+        // Saturday night is not covered
+        assert!(!schedule.is_active(utc("2023-07-01T23:00:00Z")));
+        assert_eq!(
+            schedule.next_change(utc("2023-06-30T23:00:00Z")),
+            Some(utc("2023-07-01T02:00:00Z"))
+        );
+        assert_eq!(
+            schedule.next_change(utc("2023-07-01T02:00:00Z")),
+            Some(utc("2023-07-07T22:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn windows_follow_the_time_zone() {
+        let window = json!([{ "start": "08:00", "end": "20:00" }]);
+        let helsinki = schedule(json!({ "time_zone": "Europe/Helsinki", "windows": window }));
+        let new_york = schedule(json!({ "time_zone": "America/New_York", "windows": window }));
+        // 08:00 in Helsinki and 01:00 in New York
+        let time = utc("2023-06-28T05:00:00Z");
+        assert!(helsinki.is_active(time));
+        assert!(!new_york.is_active(time));
+        assert_eq!(
+            new_york.next_change(time),
+            Some(utc("2023-06-28T12:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn windows_keep_local_time_over_daylight_saving_changes() {
+        let schedule = schedule(json!({
+            "time_zone": "Europe/Helsinki",
+            "windows": [{ "start": "08:00", "end": "20:00" }]
+        }));
+        // Summer time starts on 2023-03-26, local time moves from UTC+2 to UTC+3
+        assert!(schedule.is_active(utc("2023-03-25T06:00:00Z")));
+        assert!(!schedule.is_active(utc("2023-03-26T04:30:00Z")));
+        assert_eq!(
+            schedule.next_change(utc("2023-03-25T20:00:00Z")),
+            Some(utc("2023-03-26T05:00:00Z"))
+        );
+        // And ends on 2023-10-29
+        assert_eq!(
+            schedule.next_change(utc("2023-10-28T20:00:00Z")),
+            Some(utc("2023-10-29T06:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn skipped_and_repeated_local_times() {
+        let schedule = schedule(json!({
+            "time_zone": "Europe/Helsinki",
+            "windows": [{ "start": "03:30", "end": "05:00" }]
+        }));
+        // 03:30 does not exist on 2023-03-26, the window starts at 04:30 summer time
+        assert!(!schedule.is_active(utc("2023-03-26T01:15:00Z")));
+        assert!(schedule.is_active(utc("2023-03-26T01:30:00Z")));
+        assert!(!schedule.is_active(utc("2023-03-26T02:00:00Z")));
+        // 03:30 happens twice on 2023-10-29, the window starts at the first one
+        assert!(!schedule.is_active(utc("2023-10-29T00:15:00Z")));
+        assert!(schedule.is_active(utc("2023-10-29T00:30:00Z")));
+        assert!(schedule.is_active(utc("2023-10-29T02:45:00Z")));
+        assert!(!schedule.is_active(utc("2023-10-29T03:00:00Z")));
+    }
+
+    #[test]
+    fn date_ranges_limit_the_days() {
+        let schedule = schedule(json!({
+            "time_zone": "Europe/Helsinki",
+            "date_ranges": [
+                { "start": "12-01", "end": "01-31" },
+                { "start": "2023-06-01", "end": "2023-06-30" }
+            ]
+        }));
+        assert!(schedule.is_active(utc("2023-01-15T12:00:00Z")));
+        assert!(schedule.is_active(utc("2023-12-24T12:00:00Z")));
+        assert!(!schedule.is_active(utc("2023-03-01T12:00:00Z")));
+        assert!(schedule.is_active(utc("2023-06-15T12:00:00Z")));
+        assert!(!schedule.is_active(utc("2024-06-15T12:00:00Z")));
+        // Days start at local midnight
+        assert_eq!(
+            schedule.next_change(utc("2023-01-15T12:00:00Z")),
+            Some(utc("2023-01-31T22:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn windows_relative_to_the_sun() {
+        let schedule = schedule(json!({
+            "time_zone": "Europe/Helsinki",
+            "latitude": 60.17,
+            "longitude": 24.94,
+            "windows": [{ "start": { "sunset": -30 }, "end": { "sunrise": 30 } }]
+        }));
+        // Around midsummer the sun sets at about 22:50 and rises at about 03:55 local time
+        let start = schedule.next_change(utc("2023-06-21T12:00:00Z")).unwrap();
+        assert!((start - utc("2023-06-21T19:20:00Z")).num_minutes().abs() < 10);
+        let end = schedule.next_change(start).unwrap();
+        assert!((end - utc("2023-06-22T01:25:00Z")).num_minutes().abs() < 10);
+        assert!(schedule.is_active(utc("2023-06-21T23:00:00Z")));
+        assert!(!schedule.is_active(utc("2023-06-22T12:00:00Z")));
+    }
+
+    #[test]
+    fn no_sunset_during_the_polar_day_and_night() {
+        let schedule = schedule(json!({
+            "time_zone": "Europe/Oslo",
+            "latitude": 78.22,
+            "longitude": 15.65,
+            "windows": [{ "start": { "sunset": 0 }, "end": "23:59" }]
+        }));
+        for date in ["2023-06-21", "2023-12-21"] {
+            let date: NaiveDate = date.parse().unwrap();
+            assert_eq!(sun_event(date, 78.22, 15.65, false), None);
+            let evening = local_to_utc(Tz::Europe__Oslo, date.and_hms_opt(22, 0, 0).unwrap());
+            assert!(!schedule.is_active(evening));
+        }
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        let invalid = [
+            json!({ "time_zone": "Mars/Olympus_Mons" }),
+            json!({ "time_zone": "UTC", "date_ranges": [{ "start": "04-01", "end": "2023-07-31" }] }),
+            json!({ "time_zone": "UTC", "date_ranges": [{ "start": "02-30", "end": "03-01" }] }),
+            json!({ "time_zone": "UTC", "windows": [{ "days": ["someday"], "start": "08:00", "end": "20:00" }] }),
+            json!({ "time_zone": "UTC", "windows": [{ "start": "8am", "end": "20:00" }] }),
+            json!({ "time_zone": "UTC", "windows": [{ "start": { "sunrise": 0 }, "end": "20:00" }] }),
+        ];
+        for value in invalid {
+            let schedule: Schedule = serde_json::from_value(value.clone()).unwrap();
+            assert!(schedule.validate().is_err(), "{value}");
+        }
+    }
+}
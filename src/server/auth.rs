@@ -0,0 +1,309 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{error, http::header, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::{bail, Context, Result};
+use futures::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use log::info;
+use paperclip::actix::{Apiv2Security, OperationModifier};
+use paperclip::v2::schema::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// What a caller is allowed to see or do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    /// Drones, infringements, zones and everything else that is not personal
+    #[serde(rename = "read:public")]
+    ReadPublic,
+    /// Unredacted pilot contact details
+    #[serde(rename = "read:pii")]
+    ReadPii,
+    /// Reviewing infringements
+    #[serde(rename = "write:reviews")]
+    WriteReviews,
+    /// Recording and managing replay sessions
+    #[serde(rename = "admin:replay")]
+    AdminReplay,
+    /// Managing exemptions from the zones
+    #[serde(rename = "admin:zones")]
+    AdminZones,
+    /// Managing webhooks and their deliveries
+    #[serde(rename = "admin:webhooks")]
+    AdminWebhooks,
+    /// Listing and previewing message templates
+    #[serde(rename = "admin:templates")]
+    AdminTemplates,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadPublic => "read:public",
+            Scope::ReadPii => "read:pii",
+            Scope::WriteReviews => "write:reviews",
+            Scope::AdminReplay => "admin:replay",
+            Scope::AdminZones => "admin:zones",
+            Scope::AdminWebhooks => "admin:webhooks",
+            Scope::AdminTemplates => "admin:templates",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        serde_json::from_value(serde_json::Value::String(value.to_string()))
+            .with_context(|| format!("Unknown scope \"{value}\""))
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An api key as it is configured, only the hash of the key is stored
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    /// Who the key belongs to, used in logs
+    pub name: String,
+    /// Hex encoded SHA-256 of the key, see [hash_key]
+    pub hash: String,
+    pub scopes: BTreeSet<Scope>,
+}
+
+/// Hex encoded SHA-256 of a key, what is stored in the key file
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+lazy_static! {
+    /// Api keys by their hash
+    static ref API_KEYS: HashMap<String, ApiKey> = load_api_keys()
+        .unwrap_or_else(|e| panic!("Failed to load api keys: {e}"));
+    /// What callers without a key can do, read from BIRDNEST_ANONYMOUS_SCOPES
+    static ref ANONYMOUS_SCOPES: BTreeSet<Scope> = anonymous_scopes()
+        .unwrap_or_else(|e| panic!("Invalid BIRDNEST_ANONYMOUS_SCOPES: {e}"));
+}
+
+/// Load the keys up front, so a broken key file stops the server from starting
+pub fn init() {
+    lazy_static::initialize(&API_KEYS);
+    lazy_static::initialize(&ANONYMOUS_SCOPES);
+}
+
+/// Read from the json file given with --api-keys or BIRDNEST_API_KEYS_FILE,
+/// and the json in BIRDNEST_API_KEYS
+fn load_api_keys() -> Result<HashMap<String, ApiKey>> {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args
+        .iter()
+        .position(|a| a == "--api-keys")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("BIRDNEST_API_KEYS_FILE").ok());
+    let mut keys: Vec<ApiKey> = vec![];
+    if let Some(path) = path {
+        let json = std::fs::read_to_string(&path).with_context(|| format!("Can't read {path}"))?;
+        keys.extend(serde_json::from_str::<Vec<ApiKey>>(&json).with_context(|| path.clone())?);
+    }
+    if let Ok(json) = std::env::var("BIRDNEST_API_KEYS") {
+        keys.extend(serde_json::from_str::<Vec<ApiKey>>(&json).context("BIRDNEST_API_KEYS")?);
+    }
+    let mut by_hash = HashMap::new();
+    for mut key in keys {
+        key.hash = key.hash.to_lowercase();
+        if key.hash.len() != 64 || !key.hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("The hash of {} is not a hex encoded SHA-256", key.name);
+        }
+        if let Some(previous) = by_hash.insert(key.hash.clone(), key) {
+            bail!("{} has the same key as another key", previous.name);
+        }
+    }
+    info!("Loaded {} api keys", by_hash.len());
+    Ok(by_hash)
+}
+
+/// Space or comma separated scopes, defaults to read:public
+fn anonymous_scopes() -> Result<BTreeSet<Scope>> {
+    match std::env::var("BIRDNEST_ANONYMOUS_SCOPES") {
+        Ok(value) => value
+            .split([' ', ','])
+            .filter(|s| !s.is_empty())
+            .map(Scope::parse)
+            .collect(),
+        Err(_) => Ok(BTreeSet::from([Scope::ReadPublic])),
+    }
+}
+
+/// The scopes of the caller, resolved by [Authentication]
+#[derive(Debug, Clone)]
+pub struct Scopes {
+    /// The name of the api key, None for anonymous callers
+    pub key: Option<String>,
+    pub scopes: BTreeSet<Scope>,
+}
+
+impl Scopes {
+    pub fn has(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    fn anonymous() -> Self {
+        Self {
+            key: None,
+            scopes: ANONYMOUS_SCOPES.clone(),
+        }
+    }
+
+    /// The scopes of the key in the Authorization header, errors for unknown keys
+    fn of(req: &ServiceRequest) -> Result<Self, actix_web::Error> {
+        let Some(value) = req.headers().get(header::AUTHORIZATION) else {
+            return Ok(Self::anonymous());
+        };
+        let key = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| unauthorized("Expected Authorization: Bearer <api key>"))?;
+        let api_key = API_KEYS
+            .get(&hash_key(key.trim()))
+            .ok_or_else(|| unauthorized("Unknown api key"))?;
+        Ok(Self {
+            key: Some(api_key.name.clone()),
+            scopes: api_key.scopes.clone(),
+        })
+    }
+
+    /// Errors unless the caller has the scope
+    fn require(&self, scope: Scope) -> Result<(), actix_web::Error> {
+        match (self.has(scope), &self.key) {
+            (true, _) => Ok(()),
+            (false, None) => Err(unauthorized(&format!("Requires an api key with {scope}"))),
+            (false, Some(_)) => Err(error::ErrorForbidden(format!("Requires the {scope} scope"))),
+        }
+    }
+}
+
+fn unauthorized(message: &str) -> actix_web::Error {
+    error::InternalError::from_response(
+        message.to_string(),
+        HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .body(message.to_string()),
+    )
+    .into()
+}
+
+impl FromRequest for Scopes {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let scopes = req.extensions().get::<Scopes>().cloned();
+        ready(Ok(scopes.unwrap_or_else(Scopes::anonymous)))
+    }
+}
+
+impl Apiv2Schema for Scopes {}
+impl OperationModifier for Scopes {}
+
+/// Middleware that resolves the api key of every request into its [Scopes]
+pub struct Authentication;
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            match Scopes::of(&req) {
+                Ok(scopes) => {
+                    req.extensions_mut().insert(scopes);
+                    Ok(service.call(req).await?.map_into_left_body())
+                }
+                Err(e) => match super::limits::limit_unauthorized(&req) {
+                    Some(response) => Ok(req.into_response(response).map_into_right_body()),
+                    None => Ok(req.error_response(e).map_into_right_body()),
+                },
+            }
+        })
+    }
+}
+
+/// Api key in the Authorization header as `Bearer <key>`
+#[derive(Apiv2Security)]
+#[openapi(
+    apiKey,
+    alias = "api_key",
+    in = "header",
+    name = "Authorization",
+    description = "Bearer <api key>. Callers without a key get the scopes in BIRDNEST_ANONYMOUS_SCOPES, read:public by default. Unredacted pilot details need read:pii"
+)]
+pub struct ApiKeyScheme;
+
+/// Extractors that reject callers without a scope, documented in the OpenAPI spec
+pub mod require {
+    use super::*;
+
+    macro_rules! required_scope {
+        ($name:ident, $scope:expr, $label:literal) => {
+            #[doc = concat!("Requires the ", $label, " scope")]
+            #[derive(Apiv2Security)]
+            #[openapi(parent = "ApiKeyScheme", scopes($label))]
+            pub struct $name;
+
+            impl FromRequest for $name {
+                type Error = actix_web::Error;
+                type Future = Ready<Result<Self, Self::Error>>;
+
+                fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+                    let scopes = Scopes::from_request(req, payload)
+                        .into_inner()
+                        .expect("Scopes can always be extracted");
+                    ready(scopes.require($scope).map(|_| $name))
+                }
+            }
+        };
+    }
+
+    required_scope!(ReadPublic, Scope::ReadPublic, "read:public");
+    required_scope!(WriteReviews, Scope::WriteReviews, "write:reviews");
+    required_scope!(AdminReplay, Scope::AdminReplay, "admin:replay");
+    required_scope!(AdminZones, Scope::AdminZones, "admin:zones");
+    required_scope!(AdminWebhooks, Scope::AdminWebhooks, "admin:webhooks");
+    required_scope!(AdminTemplates, Scope::AdminTemplates, "admin:templates");
+}
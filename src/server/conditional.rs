@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    body::BoxBody,
+    http::header::{self, EntityTag, Header, IfModifiedSince, IfNoneMatch, TryIntoHeaderPair},
+    HttpRequest, HttpResponse, Responder,
+};
+use paperclip::actix::OperationModifier;
+use paperclip::v2::models::{DefaultOperationRaw, DefaultSchemaRaw, Either, Response};
+use paperclip::v2::schema::Apiv2Schema;
+
+/// Cache validators describing the version of a response
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: EntityTag,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Weak, so the same tag can be used for every negotiated encoding of a response
+    pub fn new(tag: String, last_modified: Option<SystemTime>) -> Self {
+        Self {
+            etag: EntityTag::new_weak(tag),
+            last_modified,
+        }
+    }
+
+    /// Whether the client already has this version, If-None-Match takes precedence
+    /// over If-Modified-Since like RFC 9110 requires
+    fn is_fresh(&self, req: &HttpRequest) -> bool {
+        if req.headers().contains_key(header::IF_NONE_MATCH) {
+            return match IfNoneMatch::parse(req).unwrap_or(IfNoneMatch::Items(vec![])) {
+                IfNoneMatch::Any => true,
+                IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(&self.etag)),
+            };
+        }
+        match (IfModifiedSince::parse(req), self.last_modified) {
+            (Ok(IfModifiedSince(since)), Some(last_modified)) => {
+                // Http dates only have second precision
+                let last_modified = last_modified
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()))
+                    .unwrap_or(last_modified);
+                SystemTime::from(since) >= last_modified
+            }
+            _ => false,
+        }
+    }
+
+    fn headers(&self) -> Vec<(header::HeaderName, header::HeaderValue)> {
+        let mut headers = vec![header::ETag(self.etag.clone())
+            .try_into_pair()
+            .expect("Entity tags are valid header values")];
+        if let Some(last_modified) = self.last_modified {
+            headers.push(
+                header::LastModified(last_modified.into())
+                    .try_into_pair()
+                    .expect("Http dates are valid header values"),
+            );
+        }
+        headers
+    }
+}
+
+/// Wraps a response with an ETag and Last-Modified header,
+/// answering 304 Not Modified when the client already has the same version
+pub struct Conditional<T> {
+    pub validators: Validators,
+    pub response: T,
+}
+
+impl<T: Responder> Responder for Conditional<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let mut response = if self.validators.is_fresh(req) {
+            HttpResponse::NotModified().finish()
+        } else {
+            self.response.respond_to(req).map_into_boxed_body()
+        };
+        for (name, value) in self.validators.headers() {
+            response.headers_mut().insert(name, value);
+        }
+        response
+    }
+}
+
+impl<T: Apiv2Schema> Apiv2Schema for Conditional<T> {
+    fn name() -> Option<String> {
+        T::name()
+    }
+
+    fn raw_schema() -> DefaultSchemaRaw {
+        T::raw_schema()
+    }
+}
+
+impl<T: OperationModifier> OperationModifier for Conditional<T> {
+    fn update_parameter(op: &mut DefaultOperationRaw) {
+        T::update_parameter(op);
+    }
+
+    fn update_definitions(map: &mut BTreeMap<String, DefaultSchemaRaw>) {
+        T::update_definitions(map);
+    }
+
+    fn update_response(op: &mut DefaultOperationRaw) {
+        T::update_response(op);
+        op.responses.insert(
+            "304".into(),
+            Either::Right(Response {
+                description: Some(
+                    "Not Modified, the version in If-None-Match or If-Modified-Since is current"
+                        .into(),
+                ),
+                ..Default::default()
+            }),
+        );
+    }
+}
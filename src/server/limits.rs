@@ -0,0 +1,379 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{error, http::header, HttpMessage, HttpResponse};
+use anyhow::{bail, Context, Result};
+use futures::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use moka::sync::Cache;
+
+use super::auth::Scopes;
+
+/// A token bucket that holds `requests` tokens and refills them over `per`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl RateLimit {
+    /// Parses `<requests>/<s|m|h>` like `120/m`, `off` disables the limit
+    pub fn parse(value: &str) -> Result<Option<Self>> {
+        if value == "off" {
+            return Ok(None);
+        }
+        let (requests, per) = value
+            .split_once('/')
+            .with_context(|| format!("Expected <requests>/<s|m|h>, got \"{value}\""))?;
+        let requests: u32 = requests
+            .parse()
+            .with_context(|| format!("Invalid number of requests \"{requests}\""))?;
+        let per = match per {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            other => bail!("Unknown period \"{other}\", expected s, m or h"),
+        };
+        if requests == 0 {
+            bail!("The limit must allow at least one request, use off to disable it");
+        }
+        Ok(Some(Self { requests, per }))
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.requests as f64 / self.per.as_secs_f64()
+    }
+}
+
+fn env_rate_limit(name: &str, default: &str) -> Option<RateLimit> {
+    let value = std::env::var(name).unwrap_or_else(|_| default.to_string());
+    RateLimit::parse(&value).unwrap_or_else(|e| panic!("Invalid {name}: {e}"))
+}
+
+fn env_bool(name: &str) -> bool {
+    match std::env::var(name) {
+        Err(_) => false,
+        Ok(value) => match value.to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => true,
+            "" | "0" | "false" | "no" | "off" => false,
+            _ => panic!("Invalid {name}: {value}, expected true or false"),
+        },
+    }
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid {name}: {value}"))
+        })
+        .unwrap_or(default)
+}
+
+/// Longest prefix wins, anything else gets BIRDNEST_REQUEST_TIMEOUT
+fn route_timeouts() -> Vec<(String, Duration)> {
+    let mut timeouts = vec![(
+        "/infringements/export".to_string(),
+        Duration::from_secs(120),
+    )];
+    if let Ok(value) = std::env::var("BIRDNEST_ROUTE_TIMEOUTS") {
+        for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (route, secs) = pair
+                .split_once('=')
+                .and_then(|(route, secs)| Some((route, secs.parse().ok()?)))
+                .unwrap_or_else(|| panic!("Invalid BIRDNEST_ROUTE_TIMEOUTS entry: {pair}"));
+            timeouts.retain(|(r, _)| r != route);
+            timeouts.push((route.to_string(), Duration::from_secs(secs)));
+        }
+    }
+    timeouts.sort_by_key(|(route, _)| std::cmp::Reverse(route.len()));
+    timeouts
+}
+
+struct LimitsConfig {
+    /// For callers without an api key, read from BIRDNEST_RATE_LIMIT_IP
+    ip_rate: Option<RateLimit>,
+    /// For every api key, read from BIRDNEST_RATE_LIMIT_KEY
+    key_rate: Option<RateLimit>,
+    /// Requests a single client can have in progress, read from BIRDNEST_MAX_CONCURRENT
+    max_concurrent: usize,
+    /// Read from BIRDNEST_REQUEST_TIMEOUT
+    default_timeout: Duration,
+    route_timeouts: Vec<(String, Duration)>,
+    /// Use the client address from Fly-Client-IP or X-Forwarded-For, read from BIRDNEST_TRUST_PROXY
+    trust_proxy: bool,
+}
+
+impl LimitsConfig {
+    fn from_env() -> Self {
+        Self {
+            ip_rate: env_rate_limit("BIRDNEST_RATE_LIMIT_IP", "120/m"),
+            key_rate: env_rate_limit("BIRDNEST_RATE_LIMIT_KEY", "600/m"),
+            max_concurrent: env_number("BIRDNEST_MAX_CONCURRENT", 4) as usize,
+            default_timeout: Duration::from_secs(env_number("BIRDNEST_REQUEST_TIMEOUT", 10)),
+            route_timeouts: route_timeouts(),
+            trust_proxy: env_bool("BIRDNEST_TRUST_PROXY"),
+        }
+    }
+
+    /// Only covers the response head, a streamed body like an export can take longer
+    fn timeout(&self, path: &str) -> Duration {
+        self.route_timeouts
+            .iter()
+            .find(|(route, _)| path.starts_with(route.as_str()))
+            .map(|(_, timeout)| *timeout)
+            .unwrap_or(self.default_timeout)
+    }
+}
+
+/// The bucket and the requests in progress of an ip or an api key
+struct ClientState {
+    tokens: f64,
+    refilled_at: Instant,
+    active: usize,
+}
+
+impl ClientState {
+    fn new(rate: Option<RateLimit>, now: Instant) -> Self {
+        Self {
+            tokens: rate.map(|r| r.requests as f64).unwrap_or_default(),
+            refilled_at: now,
+            active: 0,
+        }
+    }
+
+    /// Take a token, or how long until there is one
+    fn take(&mut self, rate: RateLimit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.refill_rate()).min(rate.requests as f64);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / rate.refill_rate(),
+            ))
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: LimitsConfig = LimitsConfig::from_env();
+    /// Clients are forgotten after an hour without requests
+    static ref CLIENTS: Cache<String, Arc<Mutex<ClientState>>> = Cache::builder()
+        .max_capacity(100_000)
+        .time_to_idle(Duration::from_secs(60 * 60))
+        .build();
+}
+
+/// The address the proxy saw the request come from. Fly-Client-IP is set by fly.io,
+/// otherwise the right-most X-Forwarded-For entry, the ones before it come from the client
+fn forwarded_ip(headers: &header::HeaderMap) -> Option<String> {
+    let fly = headers
+        .get("fly-client-ip")
+        .and_then(|value| value.to_str().ok());
+    let forwarded = headers
+        .get_all(header::X_FORWARDED_FOR)
+        .last()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next());
+    fly.or(forwarded)
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(str::to_string)
+}
+
+fn client_ip(req: &ServiceRequest) -> String {
+    let forwarded = CONFIG
+        .trust_proxy
+        .then(|| forwarded_ip(req.headers()))
+        .flatten();
+    forwarded
+        .or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()))
+        .unwrap_or_default()
+}
+
+fn client_state(client: String, rate: Option<RateLimit>) -> Arc<Mutex<ClientState>> {
+    CLIENTS.get_with(client, || {
+        Arc::new(Mutex::new(ClientState::new(rate, Instant::now())))
+    })
+}
+
+/// Counts a request with an invalid api key against the bucket of its ip address,
+/// so keys can't be guessed faster than the ip limit allows. Called by
+/// [super::auth::Authentication] before it rejects the request
+pub fn limit_unauthorized(req: &ServiceRequest) -> Option<HttpResponse> {
+    let rate = CONFIG.ip_rate?;
+    let state = client_state(format!("ip:{}", client_ip(req)), Some(rate));
+    let result = state.lock().unwrap().take(rate, Instant::now());
+    result
+        .err()
+        .map(|wait| too_many_requests(wait, "Rate limit exceeded"))
+}
+
+/// Frees the slot of a request once it is done
+struct ActiveRequest(Arc<Mutex<ClientState>>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().active -= 1;
+    }
+}
+
+fn too_many_requests(retry_after: Duration, message: &str) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((
+            header::RETRY_AFTER,
+            retry_after.as_secs_f64().ceil().max(1.0).to_string(),
+        ))
+        .body(message.to_string())
+}
+
+/// Rate limits, concurrency limits and timeouts, see [LimitsConfig].
+/// Must be wrapped inside of [super::auth::Authentication] to tell api keys apart
+pub struct Limits;
+
+impl<S, B> Transform<S, ServiceRequest> for Limits
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = LimitsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        lazy_static::initialize(&CONFIG);
+        ready(Ok(LimitsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct LimitsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for LimitsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let key = req
+                .extensions()
+                .get::<Scopes>()
+                .and_then(|scopes| scopes.key.clone());
+            let (client, rate) = match key {
+                Some(key) => (format!("key:{key}"), CONFIG.key_rate),
+                None => (format!("ip:{}", client_ip(&req)), CONFIG.ip_rate),
+            };
+            let state = client_state(client, rate);
+            let rejection = {
+                let mut state = state.lock().unwrap();
+                match rate.map(|rate| state.take(rate, Instant::now())) {
+                    Some(Err(wait)) => Some(too_many_requests(wait, "Rate limit exceeded")),
+                    _ if state.active >= CONFIG.max_concurrent => Some(too_many_requests(
+                        Duration::from_secs(1),
+                        "Too many concurrent requests",
+                    )),
+                    _ => {
+                        state.active += 1;
+                        None
+                    }
+                }
+            };
+            if let Some(response) = rejection {
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            let _active = ActiveRequest(state);
+            let timeout = CONFIG.timeout(req.path());
+            match tokio::time::timeout(timeout, service.call(req)).await {
+                Ok(response) => Ok(response?.map_into_left_body()),
+                Err(_) => Err(error::ErrorServiceUnavailable(format!(
+                    "Timed out after {}s",
+                    timeout.as_secs()
+                ))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PER_SECOND: RateLimit = RateLimit {
+        requests: 2,
+        per: Duration::from_secs(1),
+    };
+
+    #[test]
+    fn parses_rate_limits() {
+        assert_eq!(
+            RateLimit::parse("120/m").unwrap(),
+            Some(RateLimit {
+                requests: 120,
+                per: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(RateLimit::parse("off").unwrap(), None);
+        assert!(RateLimit::parse("0/s").is_err());
+        assert!(RateLimit::parse("10/d").is_err());
+        assert!(RateLimit::parse("10").is_err());
+    }
+
+    #[test]
+    fn bucket_empties_and_refills() {
+        let start = Instant::now();
+        let mut state = ClientState::new(Some(PER_SECOND), start);
+        assert!(state.take(PER_SECOND, start).is_ok());
+        assert!(state.take(PER_SECOND, start).is_ok());
+        let wait = state.take(PER_SECOND, start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        // Half a second refills one token
+        let later = start + Duration::from_millis(500);
+        assert!(state.take(PER_SECOND, later).is_ok());
+        assert!(state.take(PER_SECOND, later).is_err());
+    }
+
+    #[test]
+    fn bucket_holds_at_most_the_limit() {
+        let start = Instant::now();
+        let mut state = ClientState::new(Some(PER_SECOND), start);
+        let later = start + Duration::from_secs(60);
+        assert!(state.take(PER_SECOND, later).is_ok());
+        assert!(state.take(PER_SECOND, later).is_ok());
+        assert!(state.take(PER_SECOND, later).is_err());
+    }
+
+    #[test]
+    fn uses_the_address_the_proxy_saw() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::X_FORWARDED_FOR, "1.1.1.1, 2.2.2.2".parse().unwrap());
+        // The left-most entry is whatever the client sent
+        assert_eq!(forwarded_ip(&headers).as_deref(), Some("2.2.2.2"));
+        headers.insert(
+            header::HeaderName::from_static("fly-client-ip"),
+            "3.3.3.3".parse().unwrap(),
+        );
+        assert_eq!(forwarded_ip(&headers).as_deref(), Some("3.3.3.3"));
+        assert_eq!(forwarded_ip(&header::HeaderMap::new()), None);
+    }
+}
@@ -1,12 +1,54 @@
-use crate::features::replay::{get_replay_status, ReplayStatus};
-use crate::{cache::INFRINGEMENTS, Infringement};
+pub mod auth;
+pub mod conditional;
+pub mod limits;
+pub mod negotiate;
+
+use crate::clock::Clock;
+use crate::config::{zones, Ring, Tier};
+use crate::features::airspace::airspace_geojson;
+use crate::features::alerts::{self, Alert, AlertRule};
+use crate::features::exemptions::{self, Exemption, NewExemption};
+use crate::features::export::{export_stream, filter_history, ExportFormat, ExportSource};
+use crate::features::history::read_history;
+use crate::features::pilots::{self, PilotSummary};
+use crate::features::privacy::{redact_infringement, redact_infringements, REDACTION_POLICY};
+use crate::features::replay::{
+    get_replay_end_mode, get_replay_status,
+    recording::{self, RecordingLimits, RecordingProgress},
+    replay_finished,
+    session::{self, SessionManifest},
+    ReplayEndMode, ReplayStatus,
+};
+use crate::features::review::{self, Review, ReviewRequest};
+use crate::features::templates::{self, Escape, TemplateContext, TemplateInfo};
+use crate::features::webhooks::{
+    self, Delivery, DeliveryStatus, NewWebhook, Webhook, WebhookEvent,
+};
+use crate::filter::{DroneFilter, InfringementFilter};
+use crate::reaktor::{drones::Drone, pilots::Pilot};
+use crate::schedule::Schedule;
+use crate::{
+    cache::{INFRINGEMENTS, INFRINGEMENTS_REVISION, LATEST_DRONE_SNAPSHOT},
+    changes::{ChangesResponse, CHANGES},
+    events::{EventKind, ZoneEvent, EVENTS},
+    Infringement,
+};
 use actix_cors::Cors;
-use actix_web::web::redirect;
-use actix_web::{error, middleware, App, Error, HttpServer};
+use actix_web::web::{redirect, Bytes};
+use actix_web::{error, http::header, middleware, App, Error, HttpResponse, HttpServer};
 
-use chrono::DateTime;
+use auth::{require, Authentication, Scope, Scopes};
+use chrono::{DateTime, Utc};
+use conditional::{Conditional, Validators};
+use futures::StreamExt;
+use limits::Limits;
 use log::info;
+use moka::future::ConcurrentCacheExt;
+use negotiate::Negotiated;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use paperclip::actix::{
     api_v2_operation,
@@ -18,90 +60,962 @@ use paperclip::actix::{
 pub struct MetaResponse {
     pub version: String,
     pub replay_status: ReplayStatus,
+    /// The session that is being recorded or replayed, if any
+    pub replay_session: Option<String>,
+    /// What happens once the replay has played every snapshot, null when not replaying
+    pub replay_end_mode: Option<ReplayEndMode>,
+    /// Progress of the recording in progress, if any
+    pub recording: Option<RecordingProgress>,
 }
 #[api_v2_operation(summary = "Get information about this instance", tags(meta))]
-async fn meta() -> Json<MetaResponse> {
-    Json(MetaResponse {
+async fn meta(_: require::ReadPublic) -> Negotiated<MetaResponse> {
+    Negotiated(MetaResponse {
         version: env!("CARGO_PKG_VERSION").to_string(),
         replay_status: get_replay_status(),
+        replay_session: match get_replay_status() {
+            ReplayStatus::Recording => recording::recording_session_id(),
+            ReplayStatus::Replaying => session::selected_session(),
+            ReplayStatus::None => None,
+        },
+        replay_end_mode: (get_replay_status() == ReplayStatus::Replaying).then(get_replay_end_mode),
+        recording: recording::recording_progress(),
     })
 }
 
 #[derive(Deserialize, Apiv2Schema)]
-struct InfringementParams {
-    /// An optional RFC3339 time stamp,
-    /// filters out infringements that have not been updated since min_updated_at.
-    /// In javascript you can use date.toISOString();
-    #[openapi(example = "2023-01-06T13:45:40.503Z")]
-    min_updated_at: Option<String>,
+struct StartRecordingRequest {
+    /// Name of the new session, generated from the start time if left out
+    session: Option<String>,
+    #[serde(flatten)]
+    limits: RecordingLimits,
+}
+#[api_v2_operation(
+    summary = "Start recording snapshots into a new session",
+    description = "The recording stops by itself once duration_secs or max_snapshots is reached",
+    tags(replay)
+)]
+async fn start_recording(
+    _: require::AdminReplay,
+    body: Json<StartRecordingRequest>,
+) -> Result<Negotiated<RecordingProgress>, Error> {
+    let body = body.into_inner();
+    recording::start_recording(body.session, body.limits)
+        .map(Negotiated)
+        .map_err(error::ErrorBadRequest)
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct StopRecordingResponse {
+    /// The finished session, null if no snapshots were recorded
+    pub session: Option<SessionManifest>,
+}
+#[api_v2_operation(summary = "Stop the recording in progress", tags(replay))]
+async fn stop_recording(
+    _: require::AdminReplay,
+) -> Result<Negotiated<StopRecordingResponse>, Error> {
+    let session = recording::stop_recording().map_err(error::ErrorBadRequest)?;
+    Ok(Negotiated(StopRecordingResponse { session }))
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct SessionsResponse {
+    pub sessions: Vec<SessionManifest>,
+    /// The session used for replays, null means the files directly in the replay directory
+    pub selected: Option<String>,
+}
+#[api_v2_operation(summary = "List recorded sessions", tags(replay))]
+async fn list_sessions(_: require::ReadPublic) -> Negotiated<SessionsResponse> {
+    Negotiated(SessionsResponse {
+        sessions: session::list_sessions(),
+        selected: session::selected_session(),
+    })
+}
+
+#[api_v2_operation(summary = "Get the manifest of a recorded session", tags(replay))]
+async fn get_session(
+    _: require::ReadPublic,
+    id: web::Path<String>,
+) -> Result<Negotiated<SessionManifest>, Error> {
+    session::load_manifest(&id)
+        .map(Negotiated)
+        .map_err(error::ErrorNotFound)
+}
+
+#[api_v2_operation(summary = "Delete a recorded session", tags(replay))]
+async fn delete_session(
+    _: require::AdminReplay,
+    id: web::Path<String>,
+) -> Result<Negotiated<SessionsResponse>, Error> {
+    session::delete_session(&id).map_err(error::ErrorBadRequest)?;
+    Ok(list_sessions(require::ReadPublic).await)
 }
+
+#[api_v2_operation(
+    summary = "Choose the session to replay",
+    description = "Replaying restarts from the first snapshot of the chosen session",
+    tags(replay)
+)]
+async fn select_session(
+    _: require::AdminReplay,
+    id: web::Path<String>,
+) -> Result<Negotiated<SessionsResponse>, Error> {
+    session::select_session(Some(id.into_inner())).map_err(error::ErrorBadRequest)?;
+    Ok(list_sessions(require::ReadPublic).await)
+}
+
 #[derive(Serialize, Debug, Apiv2Schema)]
 pub struct InfringementResponse {
     pub infringements: Vec<Infringement>,
 }
 
+/// Timestamp of the latest drone snapshot, as reported by the sensor
+async fn snapshot_timestamp() -> Option<String> {
+    LATEST_DRONE_SNAPSHOT
+        .lock()
+        .await
+        .as_ref()
+        .map(|doc| doc.capture.snapshot_timestamp.clone())
+}
+
+/// Every infringement currently in the cache
+async fn current_infringements() -> Vec<Infringement> {
+    let cache = INFRINGEMENTS.lock().await;
+    review::with_reviews(cache.iter().map(|i| i.1).collect())
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+struct ReviewParams {
+    /// Picks a visit of the drone by the first_seen_at of its infringement,
+    /// by default the one in the cache or the latest reviewed one
+    first_seen_at: Option<DateTime<Utc>>,
+}
+
+/// The infringement of the drone in the cache, if it is the chosen visit
+async fn cached_visit(serial: &str, params: &ReviewParams) -> Option<Infringement> {
+    INFRINGEMENTS
+        .lock()
+        .await
+        .get(serial)
+        .filter(|i| params.first_seen_at.is_none_or(|at| at == i.first_seen_at))
+}
+
+#[api_v2_operation(
+    summary = "Review an infringement",
+    description = "Acknowledge, dismiss as a false positive, escalate or add a note. Reviewed infringements stay listed after the 10 minutes",
+    tags(review)
+)]
+async fn review_infringement(
+    _: require::WriteReviews,
+    serial: web::Path<String>,
+    params: Query<ReviewParams>,
+    body: Json<ReviewRequest>,
+) -> Result<Negotiated<Review>, Error> {
+    let serial = serial.into_inner();
+    let cached = cached_visit(&serial, &params).await;
+    if cached.is_none() && review::get_review(&serial, None, params.first_seen_at).is_none() {
+        return Err(error::ErrorNotFound(format!(
+            "No infringement for drone {serial}"
+        )));
+    }
+    let review = review::review(&serial, cached, params.first_seen_at, body.into_inner())
+        .map_err(error::ErrorBadRequest)?;
+    INFRINGEMENTS_REVISION.bump();
+    // Changes are tracked per drone, so only its latest visit is sent
+    if let Some(infringement) = current_infringements()
+        .await
+        .into_iter()
+        .filter(|i| i.drone_serial_number == serial)
+        .max_by_key(|i| i.first_seen_at)
+    {
+        CHANGES.lock().unwrap().upsert(&infringement);
+    }
+    Ok(Negotiated(review))
+}
+
+#[api_v2_operation(summary = "Review history of an infringement", tags(review))]
+async fn get_infringement_review(
+    _: require::ReadPublic,
+    serial: web::Path<String>,
+    params: Query<ReviewParams>,
+) -> Result<Negotiated<Review>, Error> {
+    let cached = cached_visit(&serial, &params).await;
+    review::get_review(&serial, cached.as_ref(), params.first_seen_at)
+        .map(Negotiated)
+        .ok_or_else(|| error::ErrorNotFound("The infringement has not been reviewed"))
+}
+
 #[api_v2_operation(
     summary = "List of recent infringements",
     description = "Use min_updated_at to filter older results, infringements are only stored for 10 minutes"
 )]
 async fn get_infringements(
-    params: Query<InfringementParams>,
-) -> Result<Json<InfringementResponse>, Error> {
-    let cache = INFRINGEMENTS.lock().await;
-    let mut infringements: Vec<_> = cache.iter().map(|i| i.1).collect();
-
-    // Only include infringements that have been updated since min_updated_at
-    if let Some(min_updated_at_timestamp) = &params.min_updated_at {
-        let min_updated_at = DateTime::parse_from_rfc3339(min_updated_at_timestamp)
-            .map_err(error::ErrorBadRequest)?;
-        infringements.retain(|i| {
-            DateTime::parse_from_rfc3339(&i.updated_at)
-                .expect("Failed to parse server-created time string")
-                > min_updated_at
+    _: require::ReadPublic,
+    filter: Query<InfringementFilter>,
+    scopes: Scopes,
+) -> Result<Conditional<Negotiated<InfringementResponse>>, Error> {
+    let (revision, modified_at) = INFRINGEMENTS_REVISION.get();
+    let pii = scopes.has(Scope::ReadPii);
+    let validators = Validators::new(
+        format!(
+            "{}-{revision}{}",
+            snapshot_timestamp().await.unwrap_or_default(),
+            if pii { "-pii" } else { "" }
+        ),
+        Some(modified_at),
+    );
+    let mut infringements = current_infringements().await;
+    filter
+        .apply(&mut infringements)
+        .map_err(error::ErrorBadRequest)?;
+    if !pii {
+        redact_infringements(&mut infringements);
+    }
+    Ok(Conditional {
+        validators,
+        response: Negotiated(InfringementResponse { infringements }),
+    })
+}
+
+/// Aggregate the current and archived infringements matching the filter with `aggregate`.
+/// Unsorted, the history is streamed from the file on a blocking thread
+async fn aggregate_infringements<T: Send + 'static>(
+    filter: &InfringementFilter,
+    aggregate: impl FnOnce(&mut dyn Iterator<Item = Infringement>) -> T + Send + 'static,
+) -> Result<T, Error> {
+    let matches = filter.matcher().map_err(error::ErrorBadRequest)?;
+    let current = current_infringements().await;
+    tokio::task::spawn_blocking(move || {
+        let history = read_history()?;
+        let mut infringements = pilots::deduplicate(current, history).filter(|i| matches(i));
+        anyhow::Ok(aggregate(&mut infringements))
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .map_err(error::ErrorInternalServerError)
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+struct PilotsParams {
+    /// Only pilots with at least this many infringements, 2 for repeat offenders
+    min_count: Option<usize>,
+    /// How many pilots to return at most
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct PilotsResponse {
+    /// Most infringements first
+    pub pilots: Vec<PilotSummary>,
+}
+
+#[api_v2_operation(
+    summary = "Pilots ordered by their number of infringements",
+    description = "Aggregates current and archived infringements over every drone of a pilot. Use min_updated_at and max_updated_at for a time range. Authorized and dismissed infringements are not counted",
+    tags(pilots)
+)]
+async fn get_pilots(
+    _: require::ReadPublic,
+    filter: Query<InfringementFilter>,
+    params: Query<PilotsParams>,
+    scopes: Scopes,
+) -> Result<Negotiated<PilotsResponse>, Error> {
+    let mut pilots: Vec<_> = aggregate_infringements(&filter, |all| pilots::summarize(all))
+        .await?
+        .into_iter()
+        .filter(|p| {
+            params
+                .min_count
+                .is_none_or(|min| p.infringement_count >= min)
         })
+        .take(params.limit.unwrap_or(usize::MAX))
+        .collect();
+    if !scopes.has(Scope::ReadPii) {
+        for summary in pilots.iter_mut() {
+            REDACTION_POLICY.redact_pilot(&mut summary.pilot);
+        }
+    }
+    Ok(Negotiated(PilotsResponse { pilots }))
+}
+
+// The fields of PilotSummary, Zone and Drone are written out in PilotDetails, ZoneStatus
+// and DroneDetails, paperclip leaves flattened fields out of the OpenAPI spec
+/// A pilot's summary and their infringements
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct PilotDetails {
+    pub pilot: Pilot,
+    /// How many infringements the pilot's drones have caused
+    pub infringement_count: usize,
+    /// The closest any of the pilot's drones has come to a zone center
+    pub closest_distance: f64,
+    pub worst_tier: Tier,
+    pub first_offense_at: DateTime<Utc>,
+    pub last_offense_at: DateTime<Utc>,
+    /// Serial numbers of the drones the pilot has used
+    pub drones: BTreeSet<String>,
+    /// Newest first
+    pub infringements: Vec<Infringement>,
+}
+
+#[api_v2_operation(
+    summary = "A pilot and their infringements",
+    description = "Use min_updated_at and max_updated_at for a time range",
+    tags(pilots)
+)]
+async fn get_pilot(
+    _: require::ReadPublic,
+    pilot_id: web::Path<String>,
+    filter: Query<InfringementFilter>,
+    scopes: Scopes,
+) -> Result<Negotiated<PilotDetails>, Error> {
+    let id = pilot_id.clone();
+    let mut infringements =
+        aggregate_infringements(&filter, move |all| pilots::offenses_of(&id, all)).await?;
+    let mut summary = pilots::summarize(&infringements)
+        .pop()
+        .ok_or_else(|| error::ErrorNotFound(format!("No infringements by pilot {pilot_id}")))?;
+    if !scopes.has(Scope::ReadPii) {
+        REDACTION_POLICY.redact_pilot(&mut summary.pilot);
+        redact_infringements(&mut infringements);
+    }
+    Ok(Negotiated(PilotDetails {
+        pilot: summary.pilot,
+        infringement_count: summary.infringement_count,
+        closest_distance: summary.closest_distance,
+        worst_tier: summary.worst_tier,
+        first_offense_at: summary.first_offense_at,
+        last_offense_at: summary.last_offense_at,
+        drones: summary.drones,
+        infringements,
+    }))
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+struct ChangesParams {
+    /// The next_cursor of the previous response, leave out to get every infringement
+    #[openapi(example = "18f3a2b4c5d6e7f8:42")]
+    since: Option<String>,
+}
+
+#[api_v2_operation(
+    summary = "Infringements changed since a cursor",
+    description = "Returns the additions, updates and deletions after since and the cursor for the next request. Unlike min_updated_at this is not affected by clock skew"
+)]
+async fn get_infringement_changes(
+    _: require::ReadPublic,
+    params: Query<ChangesParams>,
+    scopes: Scopes,
+) -> Result<Negotiated<ChangesResponse>, Error> {
+    // Apply pending expiries first, so they show up as deletions
+    INFRINGEMENTS.lock().await.sync();
+    let mut response = CHANGES
+        .lock()
+        .unwrap()
+        .since(params.since.as_deref())
+        .map_err(error::ErrorBadRequest)?;
+    let pii = scopes.has(Scope::ReadPii);
+    for change in response.changes.iter_mut() {
+        change.infringement = change.infringement.take().map(|infringement| {
+            let mut infringement = review::with_review(infringement);
+            if !pii {
+                redact_infringement(&mut infringement);
+            }
+            infringement
+        });
     }
-    Ok(Json(InfringementResponse { infringements }))
+    Ok(Negotiated(response))
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct ExemptionsResponse {
+    pub exemptions: Vec<Exemption>,
+}
+
+#[api_v2_operation(
+    summary = "List exemptions",
+    description = "Drones matching an exemption are marked as authorized instead of infringing. Needs admin:zones, exemptions name pilots",
+    tags(exemptions)
+)]
+async fn get_exemptions(_: require::AdminZones) -> Result<Negotiated<ExemptionsResponse>, Error> {
+    Ok(Negotiated(ExemptionsResponse {
+        exemptions: exemptions::list_exemptions(),
+    }))
+}
+
+#[api_v2_operation(
+    summary = "Add an exemption",
+    description = "Exempts a drone serial number, mac address or pilot id, optionally only for some zones or a period of time",
+    tags(exemptions)
+)]
+async fn add_exemption(
+    _: require::AdminZones,
+    body: Json<NewExemption>,
+) -> Result<Negotiated<Exemption>, Error> {
+    exemptions::add_exemption(body.into_inner())
+        .map(Negotiated)
+        .map_err(error::ErrorBadRequest)
+}
+
+#[api_v2_operation(summary = "Delete an exemption", tags(exemptions))]
+async fn delete_exemption(
+    _: require::AdminZones,
+    id: web::Path<u64>,
+) -> Result<Negotiated<ExemptionsResponse>, Error> {
+    exemptions::delete_exemption(id.into_inner()).map_err(error::ErrorNotFound)?;
+    get_exemptions(require::AdminZones).await
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct WebhooksResponse {
+    pub webhooks: Vec<Webhook>,
+}
+
+#[api_v2_operation(
+    summary = "List webhooks",
+    description = "Secrets are never returned",
+    tags(webhooks)
+)]
+async fn get_webhooks(_: require::AdminWebhooks) -> Result<Negotiated<WebhooksResponse>, Error> {
+    Ok(Negotiated(WebhooksResponse {
+        webhooks: webhooks::list_webhooks(),
+    }))
+}
+
+#[api_v2_operation(
+    summary = "Add a webhook",
+    description = "The url gets a json POST on every event, signed with HMAC-SHA256 of the body using the secret in the X-Birdnest-Signature header as sha256=<hex>",
+    tags(webhooks)
+)]
+async fn add_webhook(
+    _: require::AdminWebhooks,
+    scopes: Scopes,
+    body: Json<NewWebhook>,
+) -> Result<Negotiated<Webhook>, Error> {
+    webhooks::add_webhook(body.into_inner(), &scopes)
+        .map(Negotiated)
+        .map_err(error::ErrorBadRequest)
+}
+
+#[api_v2_operation(summary = "Delete a webhook", tags(webhooks))]
+async fn delete_webhook(
+    _: require::AdminWebhooks,
+    id: web::Path<String>,
+) -> Result<Negotiated<WebhooksResponse>, Error> {
+    webhooks::delete_webhook(&id).map_err(error::ErrorNotFound)?;
+    get_webhooks(require::AdminWebhooks).await
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+struct DeliveriesParams {
+    webhook_id: Option<String>,
+    status: Option<DeliveryStatus>,
+    /// At most this many deliveries, 100 by default
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct DeliveriesResponse {
+    pub deliveries: Vec<Delivery>,
+}
+
+#[api_v2_operation(
+    summary = "Webhook deliveries, newest first",
+    description = "Pending deliveries are retried with exponential backoff until they succeed or run out of attempts. Only the latest 1000 finished deliveries are kept. Pilot details in the bodies are redacted without read:pii",
+    tags(webhooks)
+)]
+async fn get_webhook_deliveries(
+    _: require::AdminWebhooks,
+    params: Query<DeliveriesParams>,
+    scopes: Scopes,
+) -> Result<Negotiated<DeliveriesResponse>, Error> {
+    Ok(Negotiated(DeliveriesResponse {
+        deliveries: webhooks::list_deliveries(
+            params.webhook_id.as_deref(),
+            params.status,
+            params.limit.unwrap_or(100),
+            scopes.has(Scope::ReadPii),
+        ),
+    }))
+}
+
+/// A zone and its activation state
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct ZoneStatus {
+    pub id: String,
+    pub center_x: f64,
+    pub center_y: f64,
+    /// Drones closer than this are inside the zone
+    pub radius: f64,
+    /// Severity rings, rings outside the zone only describe drones approaching it
+    pub rings: Vec<Ring>,
+    /// When the zone is active, always if left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    /// Whether drones are currently checked against the zone
+    pub active: bool,
+    /// When the zone next becomes active or inactive, missing if it never changes
+    pub next_change: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct ZonesResponse {
+    pub zones: Vec<ZoneStatus>,
+}
+
+#[api_v2_operation(
+    summary = "Zones and their activation state",
+    description = "Zones with a schedule are only checked while they are active. While replaying the time of the replayed snapshot is used",
+    tags(zones)
+)]
+async fn get_zones(
+    _: require::ReadPublic,
+    clock: web::Data<Arc<dyn Clock>>,
+) -> Result<Negotiated<ZonesResponse>, Error> {
+    let now = clock.now();
+    let zones = zones()
+        .into_iter()
+        .map(|zone| ZoneStatus {
+            active: zone.is_active(now),
+            next_change: zone
+                .schedule
+                .as_ref()
+                .and_then(|schedule| schedule.next_change(now)),
+            id: zone.id,
+            center_x: zone.center_x,
+            center_y: zone.center_y,
+            radius: zone.radius,
+            rings: zone.rings,
+            schedule: zone.schedule,
+        })
+        .collect();
+    Ok(Negotiated(ZonesResponse { zones }))
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+struct EventsParams {
+    /// Only events with a larger id
+    since: Option<u64>,
+    #[openapi(example = "ndz")]
+    zone: Option<String>,
+    drone_serial_number: Option<String>,
+    /// enter or exit
+    kind: Option<EventKind>,
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct EventsResponse {
+    pub events: Vec<ZoneEvent>,
+}
+
+#[api_v2_operation(
+    summary = "Zone entries and exits",
+    description = "Every visit of a drone to a zone starts with an enter and ends with an exit event, which includes the dwell time. Drones flying in and out of a zone show up as several visits",
+    tags(events)
+)]
+async fn get_events(
+    _: require::ReadPublic,
+    params: Query<EventsParams>,
+) -> Result<Negotiated<EventsResponse>, Error> {
+    let events = EVENTS
+        .lock()
+        .unwrap()
+        .since(params.since.unwrap_or_default())
+        .filter(|e| params.zone.as_ref().is_none_or(|zone| &e.zone == zone))
+        .filter(|e| {
+            params
+                .drone_serial_number
+                .as_ref()
+                .is_none_or(|serial| &e.drone_serial_number == serial)
+        })
+        .filter(|e| params.kind.is_none_or(|kind| e.kind == kind))
+        .cloned()
+        .collect();
+    Ok(Negotiated(EventsResponse { events }))
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct TemplatesResponse {
+    pub templates: Vec<TemplateInfo>,
+}
+
+#[api_v2_operation(
+    summary = "The loaded templates",
+    description = "The built-in templates and the ones in the templates directory, which is reloaded when its files change",
+    tags(templates)
+)]
+async fn get_templates(_: require::AdminTemplates) -> Result<Negotiated<TemplatesResponse>, Error> {
+    Ok(Negotiated(TemplatesResponse {
+        templates: templates::list_templates(),
+    }))
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+pub struct PreviewRequest {
+    /// A loaded template
+    #[openapi(example = "email/body")]
+    pub template: Option<String>,
+    /// Template source to try out instead of a loaded template
+    pub source: Option<String>,
+    /// How values in `source` are escaped, json like webhook templates by default
+    pub escape: Option<Escape>,
+    /// Render an infringement with this event, infringement.created by default
+    pub event: Option<WebhookEvent>,
+    /// The tier before the change, for infringement.tier_changed
+    pub previous_tier: Option<Tier>,
+    /// The infringement of this drone, the latest updated one by default
+    pub drone_serial_number: Option<String>,
+    /// Render this alert instead of an infringement
+    pub alert_id: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct TemplatePreview {
+    pub output: String,
+    /// What the template was rendered with
+    pub context: TemplateContext,
+}
+
+#[api_v2_operation(
+    summary = "Render a template",
+    description = "Renders a loaded template or the given source with a current infringement or a fired alert. Pilot details are redacted without the read:pii scope",
+    tags(templates)
+)]
+async fn preview_template(
+    _: require::AdminTemplates,
+    scopes: Scopes,
+    body: Json<PreviewRequest>,
+) -> Result<Negotiated<TemplatePreview>, Error> {
+    let request = body.into_inner();
+    let context = match request.alert_id {
+        Some(id) => {
+            let mut alert = alerts::list_alerts(id.checked_sub(1), None, usize::MAX)
+                .into_iter()
+                .find(|a| a.id == id)
+                .ok_or_else(|| error::ErrorNotFound(format!("No alert {id}")))?;
+            if !scopes.has(Scope::ReadPii) {
+                redact_infringements(&mut alert.infringements);
+            }
+            TemplateContext::alert(alert)
+        }
+        None => {
+            let infringements = current_infringements().await;
+            let mut infringement = match &request.drone_serial_number {
+                Some(serial) => infringements
+                    .into_iter()
+                    .find(|i| &i.drone_serial_number == serial),
+                None => infringements.into_iter().max_by_key(|i| i.updated_at),
+            }
+            .ok_or_else(|| error::ErrorNotFound("No infringement to render"))?;
+            if !scopes.has(Scope::ReadPii) {
+                redact_infringement(&mut infringement);
+            }
+            let event = request.event.unwrap_or(WebhookEvent::InfringementCreated);
+            TemplateContext::infringement(event, infringement, request.previous_tier)
+        }
+    };
+    let output = match (&request.template, &request.source) {
+        (Some(name), None) => {
+            if !templates::exists(name) {
+                return Err(error::ErrorNotFound(format!("No template {name}")));
+            }
+            templates::render(name, &context)
+        }
+        (None, Some(source)) => {
+            templates::render_source(source, request.escape.unwrap_or(Escape::Json), &context)
+        }
+        _ => return Err(error::ErrorBadRequest("Give either template or source")),
+    }
+    .map_err(|e| error::ErrorBadRequest(format!("{e:#}")))?;
+    Ok(Negotiated(TemplatePreview { output, context }))
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+struct AlertsParams {
+    /// Only alerts with a larger id
+    since: Option<u64>,
+    rule_id: Option<String>,
+    /// At most this many alerts, 100 by default
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct AlertsResponse {
+    pub alerts: Vec<Alert>,
+}
+
+#[api_v2_operation(
+    summary = "Fired alerts, newest first",
+    description = "Alerts fired during the quiet hours of their rule are listed as suppressed. Only the latest 1000 alerts are kept in memory",
+    tags(alerts)
+)]
+async fn get_alerts(
+    _: require::ReadPublic,
+    params: Query<AlertsParams>,
+    scopes: Scopes,
+) -> Result<Negotiated<AlertsResponse>, Error> {
+    let mut alerts = alerts::list_alerts(
+        params.since,
+        params.rule_id.as_deref(),
+        params.limit.unwrap_or(100),
+    );
+    if !scopes.has(Scope::ReadPii) {
+        for alert in alerts.iter_mut() {
+            redact_infringements(&mut alert.infringements);
+        }
+    }
+    Ok(Negotiated(AlertsResponse { alerts }))
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct AlertRulesResponse {
+    pub rules: Vec<AlertRule>,
+}
+
+#[api_v2_operation(summary = "The configured alert rules", tags(alerts))]
+async fn get_alert_rules(_: require::ReadPublic) -> Result<Negotiated<AlertRulesResponse>, Error> {
+    Ok(Negotiated(AlertRulesResponse {
+        rules: alerts::rules(),
+    }))
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+struct ExportParams {
+    /// csv, ndjson or geojson, defaults to csv
+    format: Option<ExportFormat>,
+    /// current for the last 10 minutes, history for infringements that have already expired
+    source: Option<ExportSource>,
+}
+
+#[api_v2_operation(
+    summary = "Export infringements as CSV, NDJSON or GeoJSON",
+    description = "Pilot fields are flattened into pilot_* columns. GeoJSON coordinates are the sensor x and y coordinates",
+    tags(export)
+)]
+async fn export_infringements(
+    _: require::ReadPublic,
+    params: Query<ExportParams>,
+    filter: Query<InfringementFilter>,
+    scopes: Scopes,
+) -> Result<HttpResponse, Error> {
+    let (params, filter) = (params.into_inner(), filter.into_inner());
+    let format = params.format.unwrap_or_default();
+    let infringements: Box<dyn Iterator<Item = Infringement> + Send> =
+        match params.source.unwrap_or_default() {
+            ExportSource::Current => {
+                let mut infringements = current_infringements().await;
+                filter
+                    .apply(&mut infringements)
+                    .map_err(error::ErrorBadRequest)?;
+                Box::new(infringements.into_iter())
+            }
+            ExportSource::History => {
+                // Reject invalid filters before the history is opened
+                if let Err(e) = filter.matcher() {
+                    return Err(error::ErrorBadRequest(e));
+                }
+                // Sorting reads the whole file
+                tokio::task::spawn_blocking(move || filter_history(&filter))
+                    .await
+                    .map_err(error::ErrorInternalServerError)?
+                    .map_err(error::ErrorInternalServerError)?
+            }
+        };
+    let pii = scopes.has(Scope::ReadPii);
+    let infringements = infringements.map(move |mut i| {
+        if !pii {
+            redact_infringement(&mut i);
+        }
+        i
+    });
+    let body = export_stream(format, infringements).map(|chunk| Ok::<_, Error>(Bytes::from(chunk)));
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"infringements.{}\"",
+                format.extension()
+            ),
+        ))
+        .streaming(body))
 }
 
 #[derive(Serialize, Debug, Apiv2Schema)]
 pub struct DronesResponse {
     /// A list of drone x-coordinates
-    pub x: Vec<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<Vec<f64>>,
     /// A list of drone y-coordinates
-    pub y: Vec<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<Vec<f64>>,
     /// A list of drone serials
-    pub serials: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serials: Option<Vec<String>>,
+    /// Every drone as an object, only included with format=objects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drones: Option<Vec<DroneDetails>>,
+}
+
+/// A drone and how close it is to every zone
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct DroneDetails {
+    pub serial_number: String,
+    pub model: String,
+    pub manufacturer: String,
+    pub mac: String,
+    pub ipv4: String,
+    pub ipv6: String,
+    pub firmware: String,
+    pub position_y: f64,
+    pub position_x: f64,
+    pub altitude: f64,
+    /// Distance from the drone to the center of every zone
+    pub zones: Vec<ZoneDistance>,
+}
+
+impl DroneDetails {
+    fn new(drone: Drone, zones: Vec<ZoneDistance>) -> Self {
+        Self {
+            serial_number: drone.serial_number,
+            model: drone.model,
+            manufacturer: drone.manufacturer,
+            mac: drone.mac,
+            ipv4: drone.ipv4,
+            ipv6: drone.ipv6,
+            firmware: drone.firmware,
+            position_y: drone.position_y,
+            position_x: drone.position_x,
+            altitude: drone.altitude,
+            zones,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Apiv2Schema)]
+pub struct ZoneDistance {
+    pub zone: String,
+    pub distance: f64,
+    pub inside: bool,
+    /// Whether the zone is active at the time of the snapshot
+    pub active: bool,
+    /// The worst ring of the zone the drone is inside of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tier: Option<Tier>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+enum DronesFormat {
+    /// Parallel x, y and serials arrays
+    #[default]
+    Arrays,
+    /// A list of drone objects with every attribute
+    Objects,
+}
+
+#[derive(Deserialize, Apiv2Schema)]
+struct DronesParams {
+    /// arrays (default) or objects
+    format: Option<DronesFormat>,
 }
 
 #[api_v2_operation(
     summary = "Drones currently within the sensors range",
-    description = "The n:th index in every array belongs to the same drone"
+    description = "By default the n:th index in every array belongs to the same drone, use format=objects to get every attribute of the drones instead"
 )]
-async fn get_drones() -> Result<Json<DronesResponse>, Error> {
-    let drones = crate::cache::LATEST_DRONE_SNAPSHOT.lock().await;
-
-    let mut x = vec![];
-    let mut y = vec![];
-    let mut serials = vec![];
+async fn get_drones(
+    _: require::ReadPublic,
+    params: Query<DronesParams>,
+    // Kept as its own extractor, numbers in flattened query structs fail to deserialize
+    filter: Query<DroneFilter>,
+) -> Result<Conditional<Negotiated<DronesResponse>>, Error> {
+    let (mut drones, timestamp) = LATEST_DRONE_SNAPSHOT
+        .lock()
+        .await
+        .as_ref()
+        .map(|doc| {
+            (
+                doc.capture.drone.clone(),
+                doc.capture.snapshot_timestamp.clone(),
+            )
+        })
+        .unwrap_or_default();
+    let validators = Validators::new(
+        timestamp.clone(),
+        DateTime::parse_from_rfc3339(&timestamp)
+            .ok()
+            .map(SystemTime::from),
+    );
+    filter.apply(&mut drones).map_err(error::ErrorBadRequest)?;
 
-    if let Some(drones) = &*drones {
-        for drone in &drones.capture.drone {
-            x.push(drone.position_x);
-            y.push(drone.position_y);
-            serials.push(drone.serial_number.clone());
+    let response = match params.format.unwrap_or_default() {
+        DronesFormat::Arrays => DronesResponse {
+            x: Some(drones.iter().map(|d| d.position_x).collect()),
+            y: Some(drones.iter().map(|d| d.position_y).collect()),
+            serials: Some(drones.iter().map(|d| d.serial_number.clone()).collect()),
+            drones: None,
+        },
+        DronesFormat::Objects => {
+            let zones = zones();
+            let snapshot_time = DateTime::parse_from_rfc3339(&timestamp)
+                .map(|t| t.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now());
+            DronesResponse {
+                x: None,
+                y: None,
+                serials: None,
+                drones: Some(
+                    drones
+                        .into_iter()
+                        .map(|drone| {
+                            let distances = zones
+                                .iter()
+                                .map(|zone| {
+                                    let distance = zone.distance_to(&drone);
+                                    ZoneDistance {
+                                        zone: zone.id.clone(),
+                                        distance,
+                                        inside: distance < zone.radius,
+                                        active: zone.is_active(snapshot_time),
+                                        tier: zone.tier_at(distance),
+                                    }
+                                })
+                                .collect();
+                            DroneDetails::new(drone, distances)
+                        })
+                        .collect(),
+                ),
+            }
         }
-    }
+    };
+    Ok(Conditional {
+        validators,
+        response: Negotiated(response),
+    })
+}
 
-    Ok(Json(DronesResponse { x, y, serials }))
+#[api_v2_operation(
+    summary = "Drones and the no-drone-zone as GeoJSON",
+    description = "A FeatureCollection with the no-drone-zone as a polygon and a point for every drone in the latest snapshot. Coordinates are the sensor x and y coordinates",
+    tags(geojson)
+)]
+async fn get_airspace(_: require::ReadPublic) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/geo+json")
+        .json(airspace_geojson().await)
 }
 
 use paperclip::v2::models::DefaultApiRaw;
 use paperclip::v2::models::Info;
-pub async fn start() -> std::io::Result<()> {
+/// Starts the api, zone schedules are evaluated with the given clock
+pub async fn start(clock: Arc<dyn Clock>) -> std::io::Result<()> {
     let http_bind = std::env::var("HTTP_BIND").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
     info!("Starting server on http://{}:...", http_bind);
+    auth::init();
+    templates::init();
+    review::init();
+    webhooks::init();
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let spec = DefaultApiRaw {
             info: Info {
                 version: env!("CARGO_PKG_VERSION").to_string(),
@@ -113,6 +1027,12 @@ pub async fn start() -> std::io::Result<()> {
         };
         // Build the api
         App::new()
+            .app_data(web::Data::new(clock.clone()))
+            // Rate limits and timeouts, runs after the api key is resolved,
+            // requests with an invalid key are limited by ip in Authentication
+            .wrap(Limits)
+            // Resolve api keys
+            .wrap(Authentication)
             // Enable CORS
             .wrap(Cors::permissive())
             // Enable logger
@@ -122,8 +1042,58 @@ pub async fn start() -> std::io::Result<()> {
             // Init routes with openapi
             .wrap_api_with_spec(spec)
             .service(web::resource("/infringements").route(web::get().to(get_infringements)))
+            .service(
+                web::resource("/infringements/changes")
+                    .route(web::get().to(get_infringement_changes)),
+            )
+            .service(
+                web::resource("/infringements/{serial}/review")
+                    .route(web::get().to(get_infringement_review))
+                    .route(web::post().to(review_infringement)),
+            )
+            .service(
+                web::resource("/infringements/export").route(web::get().to(export_infringements)),
+            )
+            .service(
+                web::resource("/exemptions")
+                    .route(web::get().to(get_exemptions))
+                    .route(web::post().to(add_exemption)),
+            )
+            .service(web::resource("/exemptions/{id}").route(web::delete().to(delete_exemption)))
+            .service(
+                web::resource("/webhooks")
+                    .route(web::get().to(get_webhooks))
+                    .route(web::post().to(add_webhook)),
+            )
+            // Before /webhooks/{id}, so it is not taken for an id
+            .service(
+                web::resource("/webhooks/deliveries").route(web::get().to(get_webhook_deliveries)),
+            )
+            .service(web::resource("/webhooks/{id}").route(web::delete().to(delete_webhook)))
+            .service(web::resource("/templates").route(web::get().to(get_templates)))
+            .service(web::resource("/templates/preview").route(web::post().to(preview_template)))
+            .service(web::resource("/pilots").route(web::get().to(get_pilots)))
+            .service(web::resource("/pilots/{pilot_id}").route(web::get().to(get_pilot)))
+            .service(web::resource("/zones").route(web::get().to(get_zones)))
+            .service(web::resource("/events").route(web::get().to(get_events)))
+            .service(web::resource("/alerts").route(web::get().to(get_alerts)))
+            .service(web::resource("/alerts/rules").route(web::get().to(get_alert_rules)))
+            .service(web::resource("/airspace.geojson").route(web::get().to(get_airspace)))
             .service(web::resource("/drones").route(web::get().to(get_drones)))
             .service(web::resource("/meta").route(web::get().to(meta)))
+            .service(web::resource("/replay/sessions").route(web::get().to(list_sessions)))
+            .service(
+                web::resource("/replay/sessions/{id}")
+                    .route(web::get().to(get_session))
+                    .route(web::delete().to(delete_session)),
+            )
+            .service(
+                web::resource("/replay/recording/start").route(web::post().to(start_recording)),
+            )
+            .service(web::resource("/replay/recording/stop").route(web::post().to(stop_recording)))
+            .service(
+                web::resource("/replay/sessions/{id}/select").route(web::post().to(select_session)),
+            )
             // Schema routes
             .with_json_spec_at("/swagger.json")
             .with_json_spec_v3_at("/openapi.json")
@@ -132,6 +1102,12 @@ pub async fn start() -> std::io::Result<()> {
             .build()
     })
     .bind(http_bind)?
-    .run()
-    .await
+    .run();
+    // Replays can be configured to stop the server once they are done
+    let handle = server.handle();
+    tokio::spawn(async move {
+        replay_finished().await;
+        handle.stop(true).await;
+    });
+    server.await
 }
@@ -0,0 +1,122 @@
+use std::collections::BTreeSet;
+
+use actix_web::{
+    body::BoxBody,
+    http::header::{self, Accept, Header},
+    HttpRequest, HttpResponse, Responder,
+};
+use paperclip::actix::OperationModifier;
+use paperclip::v2::models::{DefaultOperationRaw, DefaultSchemaRaw, Either, MediaRange, Response};
+use paperclip::v2::schema::Apiv2Schema;
+use serde::Serialize;
+
+/// Media types every [Negotiated] response can be encoded as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseFormat {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl ResponseFormat {
+    const ALL: [ResponseFormat; 3] = [
+        ResponseFormat::Json,
+        ResponseFormat::MessagePack,
+        ResponseFormat::Cbor,
+    ];
+
+    pub fn mime(&self) -> mime::Mime {
+        match self {
+            ResponseFormat::Json => mime::APPLICATION_JSON,
+            ResponseFormat::MessagePack => "application/msgpack".parse().unwrap(),
+            ResponseFormat::Cbor => "application/cbor".parse().unwrap(),
+        }
+    }
+
+    fn from_mime(mime: &mime::Mime) -> Option<Self> {
+        match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("application", "json") | ("application", "*") | ("*", "*") => {
+                Some(ResponseFormat::Json)
+            }
+            ("application", "msgpack" | "x-msgpack" | "vnd.msgpack") => {
+                Some(ResponseFormat::MessagePack)
+            }
+            ("application", "cbor") => Some(ResponseFormat::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The most preferred format in the Accept header, json if nothing supported was asked for
+    pub fn from_request(req: &HttpRequest) -> Self {
+        Accept::parse(req)
+            .ok()
+            .and_then(|accept| accept.ranked().iter().find_map(ResponseFormat::from_mime))
+            .unwrap_or(ResponseFormat::Json)
+    }
+}
+
+/// Like [paperclip::actix::web::Json], but encoded as json, MessagePack or CBOR
+/// depending on the Accept header of the request
+pub struct Negotiated<T>(pub T);
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let format = ResponseFormat::from_request(req);
+        let body = match format {
+            ResponseFormat::Json => serde_json::to_vec(&self.0).map_err(|e| e.to_string()),
+            ResponseFormat::MessagePack => {
+                rmp_serde::to_vec_named(&self.0).map_err(|e| e.to_string())
+            }
+            ResponseFormat::Cbor => {
+                let mut body = vec![];
+                ciborium::into_writer(&self.0, &mut body)
+                    .map(|_| body)
+                    .map_err(|e| e.to_string())
+            }
+        };
+        match body {
+            Ok(body) => HttpResponse::Ok()
+                .content_type(format.mime())
+                .insert_header((header::VARY, "Accept, Authorization"))
+                .body(body),
+            Err(e) => HttpResponse::InternalServerError().body(e),
+        }
+    }
+}
+
+impl<T: Apiv2Schema> Apiv2Schema for Negotiated<T> {
+    fn name() -> Option<String> {
+        T::name()
+    }
+
+    fn raw_schema() -> DefaultSchemaRaw {
+        T::raw_schema()
+    }
+}
+
+impl<T: Apiv2Schema> OperationModifier for Negotiated<T> {
+    fn update_parameter(_op: &mut DefaultOperationRaw) {}
+
+    fn update_response(op: &mut DefaultOperationRaw) {
+        op.responses.insert(
+            "200".into(),
+            Either::Right(Response {
+                description: Some("OK".into()),
+                schema: Some({
+                    let mut def = T::schema_with_ref();
+                    def.retain_ref();
+                    def
+                }),
+                ..Default::default()
+            }),
+        );
+        op.produces = Some(
+            ResponseFormat::ALL
+                .iter()
+                .map(|f| MediaRange(f.mime()))
+                .collect::<BTreeSet<_>>(),
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Drones, snapshots and infringements shared by the tests
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::{Tier, NDZ_CENTER_X, NDZ_CENTER_Y},
+    reaktor::{
+        drones::{Drone, DronesCapture, DronesDocument, DronesSensorInfo},
+        pilots::Pilot,
+    },
+    Infringement, InfringementStatus,
+};
+
+/// A drone `distance` east of the no-drone-zone center
+pub fn drone(serial: &str, distance: f64) -> Drone {
+    Drone {
+        serial_number: serial.to_string(),
+        model: "HRP-DRP 1 Pro".to_string(),
+        manufacturer: "ProDröne Ltd".to_string(),
+        mac: "00:00:00:00:00:00".to_string(),
+        ipv4: "127.0.0.1".to_string(),
+        ipv6: "::1".to_string(),
+        firmware: "1.0".to_string(),
+        position_y: NDZ_CENTER_Y,
+        position_x: NDZ_CENTER_X + distance,
+        altitude: 4000.0,
+    }
+}
+
+/// A snapshot of drones at the given distances east of the no-drone-zone center
+pub fn snapshot(timestamp: &str, drones: &[(&str, f64)]) -> DronesDocument {
+    DronesDocument {
+        device_information: DronesSensorInfo {
+            device_id: None,
+            listen_range: None,
+            device_started: None,
+            uptime_seconds: None,
+            update_interval_ms: None,
+        },
+        capture: DronesCapture {
+            snapshot_timestamp: timestamp.to_string(),
+            drone: drones
+                .iter()
+                .map(|(serial, distance)| drone(serial, *distance))
+                .collect(),
+        },
+        replay_run: None,
+    }
+}
+
+pub fn pilot(pilot_id: &str) -> Pilot {
+    Pilot {
+        pilot_id: pilot_id.to_string(),
+        first_name: "Test".to_string(),
+        last_name: "Pilot".to_string(),
+        phone_number: "+000".to_string(),
+        created_date: "2023-01-01T00:00:00.000Z".to_string(),
+        email: "test@example.com".to_string(),
+    }
+}
+
+/// An infringing drone seen once in the warning ring of the no-drone-zone at `at`
+pub fn infringement(serial: &str, at: DateTime<Utc>) -> Infringement {
+    Infringement {
+        drone_serial_number: serial.to_string(),
+        pilot: None,
+        status: InfringementStatus::Infringing,
+        exemption_id: None,
+        review: None,
+        zone: "ndz".to_string(),
+        tier: Tier::Warning,
+        severity: 0.0,
+        distance: 60_000.0,
+        x: NDZ_CENTER_X + 60_000.0,
+        y: NDZ_CENTER_Y,
+        closest_approach_at: at,
+        closest_approach_altitude: 4000.0,
+        first_seen_at: at,
+        last_seen_at: at,
+        snapshots_inside: 1,
+        updated_at: at,
+    }
+}